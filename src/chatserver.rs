@@ -2,12 +2,24 @@ extern crate actix;
 
 use actix::prelude::*;
 use crate::ChatSession;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 
 use crate::chatsession::ClientState;
 use crate::chatsession::ValidationRequest;
 use crate::chatsession::Text;
-use std::time::SystemTime;
+use crate::chatsession::SealedText;
+use crate::chatsession::ServerPacket;
+use crate::chatsession::Signal as SignalKind;
+use crate::chatsession::ErrorCode;
+use crate::chatsession::RoomPolicy;
+use crate::chatsession::Cover;
+use crate::history::{History, StoredMessage};
+use crate::envelope;
+use std::time::{SystemTime, Instant, Duration};
+
+use serde::{Serialize, Deserialize};
+
+use rand::prelude::*;
 
 pub fn get_unix_time() -> u64 {
     let systime = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
@@ -21,13 +33,54 @@ pub fn get_unix_time() -> u64 {
     }
 }
 
+//Votes needed to remove a member of a room with `members` members (counting the one voted on) ... every
+//member except the one voted on gets a say, and at least `quorum` percent of them must vote, rounded up
+//At least one vote is always needed, so one vote removes the other member of a room of two
+pub(crate) fn removal_votes_required(members: usize, quorum: u8) -> usize {
+    let eligible = members.saturating_sub(1);
+    let weighted = eligible * quorum as usize;
+    let mut required = weighted / 100;
+    if required * 100 < weighted {
+        required += 1;
+    }
+    std::cmp::max(1, required)
+}
+
+//Rounds a timestamp down to a multiple of `granularity` ... a granularity of 0 or 1 leaves it as is
+pub(crate) fn round_down(timestamp: u64, granularity: u64) -> u64 {
+    if granularity <= 1 {
+        return timestamp;
+    }
+    timestamp - timestamp % granularity
+}
+
+//Rounds a timestamp up to a multiple of `granularity`, or to u64::MAX if there is none that high
+pub(crate) fn round_up(timestamp: u64, granularity: u64) -> u64 {
+    let rounded = round_down(timestamp, granularity);
+    if rounded == timestamp {
+        return timestamp;
+    }
+    rounded.saturating_add(granularity)
+}
+
+//Blinded room ids are fixed length lowercase hex so they can't carry a room's name
+fn is_blinded_room_id(room_id: &str) -> bool {
+    room_id.len() == BLINDED_ROOM_ID_LENGTH && room_id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
 //This message should be sent to signify a client connecting to the server
 #[derive(Message)]
 pub struct Register {
     pub id: String,
     pub room_id: String,
     pub validation: String,
-    pub addr: Addr<ChatSession>
+    pub addr: Addr<ChatSession>,
+    //The client becomes the owner of the room if the room does not exist yet
+    pub claim_ownership: bool,
+    //Encrypted profile (display name, avatar hash) the client shows to the room
+    pub profile: Option<String>,
+    //One-time code handed out by a member ... lets the client in without waiting for validation
+    pub invite_code: Option<String>,
 }
 
 //This is the catch all message type for anything that needs to be communicated
@@ -37,7 +90,328 @@ pub struct Message {
     pub room_id: String,
     pub message: String,
     //Time is given in terms of seconds since UNIX epoch
-    pub timestamp: u64
+    pub timestamp: u64,
+    //Id of the message this message replies to
+    pub parent_id: Option<u64>,
+    //Seconds the message should live for before the server purges it
+    pub ttl: Option<u64>,
+    //Membership epoch the message was encrypted for ... messages for an old epoch are refused
+    pub epoch: Option<u64>,
+    //Optional client supplied key ... resending a message with the same key will not post it twice
+    pub idempotency_key: Option<String>,
+}
+
+//This message is sent by a client to post a text without revealing who sent it ... the sender's
+//identity is inside the ciphertext and the server only knows which socket the text came from
+#[derive(Message)]
+pub struct SealedMessage {
+    pub addr: Addr<ChatSession>,
+    pub room_id: String,
+    pub message: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub timestamp: u64,
+    //Id of the message this message replies to
+    pub parent_id: Option<u64>,
+    //Seconds the message should live for before the server purges it
+    pub ttl: Option<u64>,
+    //Membership epoch the message was encrypted for ... messages for an old epoch are refused
+    pub epoch: Option<u64>,
+}
+
+//This message carries an ephemeral signal (eg. typing) that is relayed but never stored
+#[derive(Message)]
+pub struct Signal {
+    pub id: String,
+    pub room_id: String,
+    pub signal: SignalKind,
+}
+
+//This message is sent by a client to replace the contents of a message it has sent
+#[derive(Message)]
+pub struct Edit {
+    pub id: String,
+    pub room_id: String,
+    pub message_id: u64,
+    pub message: String,
+}
+
+//This message is sent by a client to retract a message
+#[derive(Message)]
+pub struct Delete {
+    pub id: String,
+    pub room_id: String,
+    pub message_id: u64,
+}
+
+//This message is sent by a client to have a text delivered to its room at a later time
+#[derive(Message)]
+pub struct Schedule {
+    pub id: String,
+    pub room_id: String,
+    pub message: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub deliver_at: u64,
+    pub ttl: Option<u64>,
+}
+
+//This message is sent by a client to list the texts it has scheduled that are still pending
+#[derive(Message)]
+pub struct ListScheduled {
+    pub id: String,
+}
+
+//This message is sent by a client to cancel a text it has scheduled
+#[derive(Message)]
+pub struct CancelScheduled {
+    pub id: String,
+    pub schedule_id: u64,
+}
+
+//This message is sent by a client to fetch the stored messages of a thread
+#[derive(Message)]
+pub struct ThreadQuery {
+    pub id: String,
+    pub room_id: String,
+    //Id of the message that started the thread
+    pub message_id: u64,
+}
+
+//This message is sent by a client to react to a message ... a reaction of `None` withdraws the client's reaction
+#[derive(Message)]
+pub struct React {
+    pub id: String,
+    pub room_id: String,
+    pub message_id: u64,
+    pub reaction: Option<String>,
+}
+
+//This message is sent by the owner of a room to grant or revoke admin rights
+#[derive(Message)]
+pub struct SetAdmin {
+    pub id: String,
+    pub room_id: String,
+    //session id of the client gaining or losing admin rights
+    pub target_id: String,
+    pub admin: bool,
+}
+
+//This message is sent by a session to acknowledge that one of its peers' messages has
+//been delivered to or read by its WebSocket client
+#[derive(Message)]
+pub struct Receipt {
+    //session id of the recipient
+    pub id: String,
+    pub message_id: u64,
+    //True => the client has read the message & False => the message was only delivered
+    pub read: bool,
+}
+
+//This message is sent by a validated client to turn receipts on or off for its room
+#[derive(Message)]
+pub struct SetReceipts {
+    pub id: String,
+    pub room_id: String,
+    pub enabled: bool,
+}
+
+//This message is sent by a client to set how long messages in its room live for
+//Rooms with an owner only let admins change this
+#[derive(Message)]
+pub struct SetRoomTtl {
+    pub id: String,
+    pub room_id: String,
+    //Seconds ... `None` keeps messages until they fall out of the history
+    pub ttl: Option<u64>,
+}
+
+//This message is sent by a client to replace the encrypted metadata (name, topic, settings) of its room
+#[derive(Message)]
+pub struct SetMetadata {
+    pub id: String,
+    pub room_id: String,
+    //Version of the metadata the client has seen ... the update only goes through if it is still current
+    pub version: u64,
+    pub metadata: String,
+}
+
+//This message is sent by a client to update the encrypted profile it shows to its room
+#[derive(Message)]
+pub struct SetProfile {
+    pub id: String,
+    pub room_id: String,
+    pub profile: Option<String>,
+}
+
+//This message is sent by the owner of a room to turn announcement mode on or off
+//Only admins can send texts while a room is read-only
+#[derive(Message)]
+pub struct SetReadOnly {
+    pub id: String,
+    pub room_id: String,
+    pub read_only: bool,
+}
+
+//This message is sent by an admin of a room to stop or resume admitting newcomers
+#[derive(Message)]
+pub struct SetLocked {
+    pub id: String,
+    pub room_id: String,
+    pub locked: bool,
+}
+
+//This message is sent by a validated client to admit a newcomer ahead of time
+//The matching registration is admitted as soon as it arrives without asking the room
+#[derive(Message)]
+pub struct PreAuthorize {
+    pub id: String,
+    pub room_id: String,
+    pub admission: Admission,
+}
+
+//Who a pre-authorization admits
+pub enum Admission {
+    //A specific session id
+    Identity(String),
+    //Whoever registers with this one-time invite code first
+    InviteCode(String),
+}
+
+//This message is sent by a validated client to vote for removing a member from its room
+//The first vote proposes the removal ... the member is evicted once the room's quorum is reached
+#[derive(Message)]
+pub struct VoteRemove {
+    pub id: String,
+    pub room_id: String,
+    //session id of the member to remove
+    pub target_id: String,
+}
+
+//This message is sent by a client to set the share of members needed to remove someone from its room
+#[derive(Message)]
+pub struct SetRemovalQuorum {
+    pub id: String,
+    pub room_id: String,
+    //Percentage of the other members that must vote
+    pub quorum: u8,
+}
+
+//This message is sent by a validated client to hand new key material to its room for an epoch
+#[derive(Message)]
+pub struct Rekey {
+    pub id: String,
+    pub room_id: String,
+    pub epoch: u64,
+    //Key material encrypted by the client ... opaque to the server
+    pub payload: String,
+}
+
+//Group key agreement handshake messages (MLS) ... the server can't read them but orders them
+pub enum Handshake {
+    //Proposes a change to the group ... relayed to the room for the current group epoch
+    Proposal {
+        epoch: u64,
+        payload: String,
+    },
+    //Applies proposals and moves the group to the next epoch ... only the first commit of an epoch is accepted
+    Commit {
+        epoch: u64,
+        payload: String,
+    },
+    //Lets joiners into the group ... only the client whose commit started the epoch may send it
+    Welcome {
+        epoch: u64,
+        //session ids of the joiners
+        recipients: Vec<String>,
+        payload: String,
+    },
+}
+
+//This message is sent by a validated client to take part in its room's group key agreement
+#[derive(Message)]
+pub struct HandshakeMessage {
+    pub id: String,
+    pub room_id: String,
+    pub handshake: Handshake,
+}
+
+//This message is sent by a validated client to hand its sender key for the current group epoch to each member
+#[derive(Message)]
+pub struct SenderKeys {
+    pub id: String,
+    pub room_id: String,
+    pub epoch: u64,
+    //Maps recipient session ids to the sender key bundle encrypted for them
+    pub bundles: HashMap<String, String>,
+}
+
+//A sender key bundle waiting for its recipient to join the room
+struct PendingSenderKey {
+    //session id of the sender
+    id: String,
+    epoch: u64,
+    bundle: String,
+}
+
+//This message is sent by a client to turn strict envelope checking on or off for its room
+#[derive(Message)]
+pub struct SetStrictMode {
+    pub id: String,
+    pub room_id: String,
+    pub strict: bool,
+}
+
+//This message is sent by a client to set the padding buckets texts in its room must fit
+#[derive(Message)]
+pub struct SetPadding {
+    pub id: String,
+    pub room_id: String,
+    //Ciphertext lengths in bytes ... empty turns padding off
+    pub buckets: Vec<usize>,
+}
+
+//This message is sent by a client to turn cover traffic on or off for its room
+#[derive(Message)]
+pub struct SetCoverTraffic {
+    pub id: String,
+    pub room_id: String,
+    //Average seconds between dummy frames sent to each member ... `None` turns cover traffic off
+    pub interval: Option<u64>,
+}
+
+//Server wide caps on cover traffic ... read from the server's configuration
+#[derive(Deserialize, Clone)]
+pub struct CoverTrafficConfig {
+    //Most dummy frames sent per second across every room
+    pub max_frames_per_second: usize,
+    //Most bytes of dummy frames sent per second across every room
+    pub max_bytes_per_second: usize,
+}
+
+impl Default for CoverTrafficConfig {
+    fn default() -> CoverTrafficConfig {
+        CoverTrafficConfig {
+            max_frames_per_second: 100,
+            max_bytes_per_second: 64 * 1024,
+        }
+    }
+}
+
+//Server wide privacy settings ... read from the server's configuration
+#[derive(Deserialize, Clone)]
+pub struct PrivacyConfig {
+    //Leave session ids, room ids and connection counts out of the logs
+    pub enabled: bool,
+    //Seconds that timestamps on relayed messages are rounded down to ... only used when privacy mode is on
+    pub timestamp_granularity: u64,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> PrivacyConfig {
+        PrivacyConfig {
+            enabled: false,
+            timestamp_granularity: 1,
+        }
+    }
 }
 
 //This message is sent by a client to disconnect from a room or from the server completely
@@ -49,163 +423,875 @@ pub struct Disconnect {
     pub full_disconnect: bool,
 }
 
+//This message is sent when a client leaves its room or withdraws its pending request to join one
+//The client stays connected to the server and is moved back to the CONNECTED state
+#[derive(Message)]
+pub struct Leave {
+    //session id
+    pub id: String,
+}
+
 //Server's bookkeeping of each client's session state
 struct Client {
     room_id: String,
     state: ClientState,
     client_addr: Addr<ChatSession>,
+    //Encrypted profile the client shows to its room ... opaque to the server
+    profile: Option<String>,
+}
+
+//A member of a room as it is shown in rosters and join events
+#[derive(Serialize, Clone)]
+pub struct Member {
+    pub id: String,
+    pub profile: Option<String>,
+}
+
+//Server's bookkeeping of each room
+struct Room {
+    //Validated clients in the room
+    members: HashSet<Addr<ChatSession>>,
+    //Delivery and read receipts are only collected when enabled ... members may turn them off for privacy
+    receipts_enabled: bool,
+    //Most recent messages sent in the room
+    history: History,
+    //Session id of the client that created the room ... auto-created and emptied rooms have no owner
+    owner: Option<String>,
+    //Session ids of clients the owner has granted admin rights to
+    admins: HashSet<String>,
+    //Seconds every message in the room lives for ... messages may ask for a shorter time
+    ttl: Option<u64>,
+    //Encrypted name, topic and settings shared by the members ... opaque to the server
+    metadata: String,
+    //Bumped on every update to the metadata ... 0 means the metadata was never set
+    metadata_version: u64,
+    //Announcement rooms only let admins send texts ... everyone else can only listen
+    read_only: bool,
+    //Locked rooms refuse every registration ... members already inside stay
+    locked: bool,
+    //Session ids that members have admitted ahead of time ... each is used up by one registration
+    preauthorized_ids: HashSet<String>,
+    //One-time invite codes handed out by members
+    invite_codes: HashSet<String>,
+    //Maps members proposed for removal to the session ids that voted for it
+    removal_votes: HashMap<String, HashSet<String>>,
+    //Percentage of the other members that must vote to remove someone
+    removal_quorum: u8,
+    //Session ids that have been voted out ... the same connection can't register into the room again
+    //Session ids are random per connection so a member that reconnects is not recognized
+    removed_ids: HashSet<String>,
+    //Membership epoch ... increases every time someone joins or leaves the room
+    epoch: u64,
+    //Epoch of the room's group key agreement ... increases with every accepted commit
+    group_epoch: u64,
+    //Session id of the client whose commit started the current group epoch
+    group_committer: Option<String>,
+    //Maps session ids that have yet to join to the sender key bundles addressed to them
+    pending_sender_keys: HashMap<String, Vec<PendingSenderKey>>,
+    //Rules every member's session enforces on the packets its client sends
+    policy: RoomPolicy,
+    //Average seconds between dummy frames sent to each member ... `None` if cover traffic is off
+    cover_interval: Option<u64>,
+}
+
+impl Room {
+    fn new() -> Room {
+        Room {
+            members: HashSet::new(),
+            receipts_enabled: true,
+            history: History::new(),
+            owner: None,
+            admins: HashSet::new(),
+            ttl: None,
+            metadata: String::new(),
+            metadata_version: 0,
+            read_only: false,
+            locked: false,
+            preauthorized_ids: HashSet::new(),
+            invite_codes: HashSet::new(),
+            removal_votes: HashMap::new(),
+            removal_quorum: DEFAULT_REMOVAL_QUORUM,
+            removed_ids: HashSet::new(),
+            epoch: 0,
+            group_epoch: 0,
+            group_committer: None,
+            pending_sender_keys: HashMap::new(),
+            policy: RoomPolicy::default(),
+            cover_interval: None,
+        }
+    }
+
+    //The owner is always an admin of the room
+    fn is_admin(&self, id: &String) -> bool {
+        self.owner.as_ref() == Some(id) || self.admins.contains(id)
+    }
+
+    //Uses up a pre-authorization matching the registration if there is one
+    fn take_preauthorization(&mut self, id: &String, invite_code: &Option<String>) -> bool {
+        if self.preauthorized_ids.remove(id) {
+            return true;
+        }
+        match invite_code {
+            None => false,
+            Some(code) => self.invite_codes.remove(code),
+        }
+    }
+
+    //Called once the last member leaves ... session ids don't outlive their connection, so nobody
+    //could ever unlock the room or speak in it again if it kept its owner, admins and restrictions
+    //Whoever registers next is admitted without being validated, so the history and metadata go too
+    fn release(&mut self) {
+        self.owner = None;
+        self.admins.clear();
+        self.read_only = false;
+        self.locked = false;
+        self.history = History::new();
+        self.metadata = String::new();
+        self.metadata_version = 0;
+        //Nobody is left in the group ... the next members start a new one
+        self.group_epoch = 0;
+        self.group_committer = None;
+        self.pending_sender_keys.clear();
+    }
+
+    //Rooms without an owner can be configured by any member
+    fn may_configure(&self, id: &String) -> bool {
+        self.owner.is_none() || self.is_admin(id)
+    }
+}
+
+//A text held by the server until its delivery time ... delivered even if the sender has disconnected
+#[derive(Serialize, Clone)]
+pub struct ScheduledMessage {
+    pub schedule_id: u64,
+    //Session id of the sender
+    #[serde(skip)]
+    pub id: String,
+    pub room_id: String,
+    pub message: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub deliver_at: u64,
+    pub ttl: Option<u64>,
 }
 
+//Receipts collected for a single message ... reported back to the sender as they come in
+struct MessageReceipts {
+    room_id: String,
+    sender_id: String,
+    delivered: HashSet<String>,
+    read: HashSet<String>,
+}
+
+//Only remember receipts for the most recent messages
+const MAX_TRACKED_RECEIPTS: usize = 1024;
+//Messages resent with the same idempotency key within this window are dropped as duplicates
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(300);
+//How often the server looks for messages that have outlived their time to live or are due for delivery
+const TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+//Largest encrypted metadata blob a room will store
+const MAX_METADATA_LENGTH: usize = 4096;
+//Largest encrypted profile a client can show to its room
+const MAX_PROFILE_LENGTH: usize = 1024;
+//How often the server decides whether to send dummy frames
+const COVER_TICK: Duration = Duration::from_secs(1);
+//Dummy frames are sent to a member at most once per tick
+const MIN_COVER_INTERVAL: u64 = 1;
+//Ciphertext length of dummy frames in rooms without padding buckets
+const DEFAULT_COVER_LENGTH: usize = 64;
+//Blinded room ids are the hex encoded HMAC-SHA256 of the room secret
+const BLINDED_ROOM_ID_LENGTH: usize = 64;
+//Rooms can only have so many padding buckets
+const MAX_PADDING_BUCKETS: usize = 16;
+//Envelopes of the largest bucket still fit in a 64 KiB WebSocket frame
+const MAX_PADDING_BUCKET: usize = 32 * 1024;
+//Rooms only queue so many sender key bundles for clients that have yet to join
+const MAX_PENDING_SENDER_KEYS: usize = 256;
+//Each sender only gets its share of the queue so one member can't crowd out everyone else's bundles
+const MAX_PENDING_SENDER_KEYS_PER_SENDER: usize = 16;
+//By default a majority of the other members must vote to remove someone
+const DEFAULT_REMOVAL_QUORUM: u8 = 51;
+//Rooms can only hold so many unused pre-authorizations
+const MAX_PREAUTHORIZATIONS: usize = 64;
+//Clients can only have so many scheduled texts pending at once
+const MAX_SCHEDULED_PER_CLIENT: usize = 16;
+//Session ids change on every connection ... rooms and the server as a whole have their own caps
+const MAX_SCHEDULED_PER_ROOM: usize = 256;
+const MAX_SCHEDULED: usize = 4096;
+//Texts can be scheduled at most a week ahead
+const MAX_SCHEDULE_HORIZON: u64 = 7 * 24 * 60 * 60;
+
 //Internal state of the chat server
 pub struct ChatServer {
     //Rooms have their own set of connecting clients
-    //Maps room ids to list of clients
-    rooms: HashMap<String, HashSet<Addr<ChatSession>>>,
+    //Maps room ids to rooms
+    rooms: HashMap<String, Room>,
     //Match ids to Client Actors
-    clients: HashMap<String, Client>
+    clients: HashMap<String, Client>,
+    //Every message relayed by the server is given a unique id
+    next_message_id: u64,
+    //Maps message ids to the receipts collected for them
+    receipts: HashMap<u64, MessageReceipts>,
+    //Message ids in `receipts` from oldest to newest
+    receipt_order: VecDeque<u64>,
+    //Maps (session id, idempotency key) to the id given to the message and when it was sent
+    idempotency_keys: HashMap<(String, String), (u64, Instant)>,
+    //Maps schedule ids to texts waiting for their delivery time
+    scheduled: HashMap<u64, ScheduledMessage>,
+    //(delivery time, schedule id) of every text in `scheduled` ... due texts are found without a full scan
+    schedule_queue: BTreeSet<(u64, u64)>,
+    next_schedule_id: u64,
+    cover_traffic: CoverTrafficConfig,
+    //Only blinded room ids are accepted and room ids are kept out of the logs
+    blinded_room_ids: bool,
+    privacy: PrivacyConfig,
 }
 
 impl ChatServer {
-    pub fn new() -> ChatServer {
+    pub fn new(cover_traffic: CoverTrafficConfig, blinded_room_ids: bool, privacy: PrivacyConfig) -> ChatServer {
         ChatServer { 
             rooms: HashMap::new(),
-            clients: HashMap::new()
+            clients: HashMap::new(),
+            next_message_id: 0,
+            receipts: HashMap::new(),
+            receipt_order: VecDeque::new(),
+            idempotency_keys: HashMap::new(),
+            scheduled: HashMap::new(),
+            schedule_queue: BTreeSet::new(),
+            next_schedule_id: 0,
+            cover_traffic,
+            blinded_room_ids,
+            privacy,
+        }
+    }
+
+    //How a room is referred to in the logs ... blinded room ids are never logged
+    fn room_label(&self, room_id: &String) -> String {
+        if self.blinded_room_ids || self.privacy.enabled {
+            "a room".to_string()
+        } else {
+            format!("room {}", room_id)
+        }
+    }
+
+    //How a user is referred to in the logs ... session ids are never logged in privacy mode
+    fn user_label(&self, id: &String) -> String {
+        if self.privacy.enabled {
+            "A user".to_string()
+        } else {
+            format!("User {}", id)
+        }
+    }
+
+    fn log_client_count(&self) {
+        if !self.privacy.enabled {
+            println!("Number of clients: {}", self.clients.len());
+        }
+    }
+
+    //Rounds a timestamp down to the configured granularity in privacy mode so relayed messages
+    //don't reveal exactly when they were sent
+    fn round_timestamp(&self, timestamp: u64) -> u64 {
+        if !self.privacy.enabled {
+            return timestamp;
+        }
+        round_down(timestamp, self.privacy.timestamp_granularity)
+    }
+
+    //Rounds a time up to the configured granularity in privacy mode ... used for expiry times so
+    //messages never disappear earlier than asked
+    fn round_timestamp_up(&self, timestamp: u64) -> u64 {
+        if !self.privacy.enabled {
+            return timestamp;
         }
+        round_up(timestamp, self.privacy.timestamp_granularity)
     }
 
-    //Broadcasts a message to every client in a room
-    pub fn broadcast_message(&self, room_id: String, id: String, message: String, timestamp: u64) {
-        match self.rooms.get(&room_id) {
+    //Broadcasts a message to every client in a room and returns the id given to the message
+    pub fn broadcast_message(&mut self, room_id: String, id: String, mut message: String, timestamp: u64) -> u64 {
+        //Server messages look like any other text in rooms that pad their messages
+        if let Some(room) = self.rooms.get(&room_id) {
+            envelope::pad_to_bucket(&mut message, &room.policy.padding_buckets);
+        }
+        let message_id = self.take_message_id();
+        let timestamp = self.round_timestamp(timestamp);
+        self.broadcast_text(&room_id, Text {
+            message_id,
+            id,
+            message,
+            timestamp,
+            parent_id: None,
+            expires_at: None,
+            epoch: None,
+        });
+        message_id
+    }
+
+    //Sends a text to every client in a room
+    fn broadcast_text(&self, room_id: &String, text: Text) {
+        match self.rooms.get(room_id) {
             None => {},
-            Some(client_list) => {
-                for client in client_list.iter() {
-                    client.do_send(Text {
-                        id: id.clone(),
-                        message: message.clone(),
-                        timestamp
-                    });
+            Some(room) => {
+                for client in room.members.iter() {
+                    client.do_send(text.clone());
                 }
             },
         }
     }
 
+    fn take_message_id(&mut self) -> u64 {
+        let message_id = self.next_message_id;
+        self.next_message_id += 1;
+        message_id
+    }
+
     pub fn get_user_count(&self, room_id: &String) -> usize {
         match self.rooms.get(room_id) {
             None => {
                 return 0;
             },
-            Some(client_list) => {
-                return client_list.len();
+            Some(room) => {
+                return room.members.len();
             },
         }
     }
 
-}
-
-impl Actor for ChatServer {
-    type Context = Context<Self>;
-}
-
-//Client is attempting to join a room
-impl Handler<Register> for ChatServer {
-    type Result = ();
-
-    fn handle(&mut self, registration: Register, ctx: &mut Self::Context) -> Self::Result {
-        //Handle case where client switches to another room ... send a disconnect signal
-        self.handle(Disconnect {
-            id: registration.id.clone(),
-            full_disconnect: false,
-        }, ctx);
-
+    //Validates a client into a room and lets the room know
+    fn admit(&mut self, id: String, room_id: String, addr: Addr<ChatSession>, profile: Option<String>) {
+        self.rooms.entry(room_id.clone())
+            .or_insert_with(Room::new)
+            .members
+            .insert(addr.clone());
+        self.clients.insert(id.clone(), Client {
+            room_id: room_id.clone(),
+            state: ClientState::VALIDATED(room_id.clone()),
+            client_addr: addr.clone(),
+            profile: profile.clone(),
+        });
+        self.broadcast_packet(&room_id, ServerPacket::Joined {
+            member: Member {
+                id: id.clone(),
+                profile,
+            },
+        });
 
-        match self.rooms.get_mut(&registration.room_id) {
-            None => {
-                //Room doesn't exist...make a new one
-                //Register client and update the server's internal state
-                let mut hashset: HashSet<Addr<ChatSession>> = HashSet::new();
-                hashset.insert(registration.addr.clone());
-
-                self.rooms.insert(registration.room_id.clone(), hashset);
-                self.clients.insert(registration.id.clone(), Client {
-                    room_id: registration.room_id.clone(),
-                    state: ClientState::VALIDATED(registration.room_id.clone()),
-                    client_addr: registration.addr.clone()
+        addr.do_send(ClientState::VALIDATED(room_id.clone()));
+        //Let the newcomer catch up on the room's rules, who is in the room, the room's metadata and what has been said
+        if let Some(room) = self.rooms.get(&room_id) {
+            addr.do_send(room.policy.clone());
+        }
+        addr.do_send(ServerPacket::Roster {
+            members: self.roster(&room_id),
+        });
+        if let Some(room) = self.rooms.get(&room_id) {
+            if room.metadata_version > 0 {
+                addr.do_send(ServerPacket::Metadata {
+                    version: room.metadata_version,
+                    metadata: room.metadata.clone(),
                 });
-
-                registration.addr.do_send(ClientState::VALIDATED(registration.room_id.clone()));
-                println!("User {} has join room {}", registration.id, registration.room_id);
-                self.broadcast_message(registration.room_id.clone(),
-                                       "Server".to_string(),
-                                       format!("User {} has join the room ... Number of connected users: {}", registration.id, self.get_user_count(&registration.room_id)),
-                                        get_unix_time());
-            },
-            Some(client_list) => {
-                if client_list.is_empty() {
-                    //Room exists but is empty ... validate and register the connecting client
-                    client_list.insert(registration.addr.clone());
-                    self.clients.insert(registration.id.clone(), Client {
-                        room_id: registration.room_id.clone(),
-                        state: ClientState::VALIDATED(registration.room_id.clone()),
-                        client_addr: registration.addr.clone()
-                    });
-
-                    registration.addr.do_send(ClientState::VALIDATED(registration.room_id.clone()));
-                    println!("User {} has join room {}", registration.id, registration.room_id);
-                    self.broadcast_message(registration.room_id.clone(),
-                                           "Server".to_string(),
-                                           format!("User {} has join the room ... Number of connected users: {}", registration.id, self.get_user_count(&registration.room_id)),
-                                           get_unix_time());
-
-                } else {
-                    //Send a validation request to every client in the room
-                    for client in client_list.iter() {
-                        client.do_send(ValidationRequest {
-                            room_id: registration.room_id.clone(),
-                            id: registration.id.clone(),
-                            validation: registration.validation.clone(),
-                            accept: false,
-                        });
-                    }
-                    //Register the client but do not add into room
-                    self.clients.insert(registration.id.clone(), Client {
-                        room_id: registration.room_id.clone(),
-                        state: ClientState::AWAITING_VALIDATION,
-                        client_addr: registration.addr.clone()
+            }
+            addr.do_send(ServerPacket::History {
+                messages: room.history.replay(),
+            });
+            addr.do_send(ServerPacket::GroupEpoch {
+                epoch: room.group_epoch,
+                committer: room.group_committer.clone(),
+            });
+        }
+        //Hand over sender keys that were sent before the newcomer joined
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            if let Some(pending) = room.pending_sender_keys.remove(&id) {
+                for sender_key in pending.into_iter().filter(|sender_key| sender_key.epoch == room.group_epoch) {
+                    addr.do_send(ServerPacket::SenderKey {
+                        id: sender_key.id,
+                        epoch: sender_key.epoch,
+                        bundle: sender_key.bundle,
                     });
-                    registration.addr.do_send(ClientState::AWAITING_VALIDATION);
                 }
-            },
+            }
         }
-        println!("Number of clients: {}", self.clients.len());
+        println!("{} has join {}", self.user_label(&id), self.room_label(&room_id));
+        let user_count = self.get_user_count(&room_id);
+        self.broadcast_message(room_id.clone(),
+                               "Server".to_string(),
+                               format!("User {} has join the room ... Number of connected users: {}", id, user_count),
+                               get_unix_time());
+        self.advance_epoch(&room_id);
     }
-}
 
-impl Handler<ValidationRequest> for ChatServer {
-    type Result = ();
+    //Starts a new membership epoch for a room ... called on every join and leave so that
+    //members can rotate their keys and shut out anyone who is no longer in the room
+    fn advance_epoch(&mut self, room_id: &String) {
+        let members: Vec<String> = self.roster(room_id).into_iter().map(|member| member.id).collect();
+        let epoch = match self.rooms.get_mut(room_id) {
+            None => return,
+            Some(room) => {
+                room.epoch += 1;
+                room.epoch
+            },
+        };
+        self.broadcast_packet(room_id, ServerPacket::EpochChanged {
+            epoch,
+            members,
+        });
+    }
 
-    fn handle(&mut self, msg: ValidationRequest, _ctx: &mut Self::Context) -> Self::Result {
-        //Don't validate client with peers who don't accept
-        if msg.accept {
-            match self.clients.get_mut(&msg.id) {
-                //Fail silently for bogus validation requests that attempt to validate a non-existent user
-                None => {},
-                Some(client) => {
-                    match self.rooms.get_mut(&msg.room_id) {
-                        None => {},
-                        Some(client_list) => {
-                            //All sanity checks passed ... validate the client
-                            client.state = ClientState::VALIDATED(msg.room_id.clone());
-                            client.client_addr.do_send(ClientState::VALIDATED(msg.room_id.clone()));
-                            client_list.insert(client.client_addr.clone());
-                            self.broadcast_message(msg.room_id.clone(),
-                                                   "Server".to_string(),
-                                                   format!("User {} has join the room ... Number of connected users: {}", msg.id, self.get_user_count(&msg.room_id)),
-                                                   get_unix_time());
+    //Validated members of a room
+    fn roster(&self, room_id: &String) -> Vec<Member> {
+        self.clients.iter()
+            .filter(|(_, client)| match &client.state {
+                ClientState::VALIDATED(validated_room_id) => validated_room_id == room_id,
+                _ => false,
+            })
+            .map(|(id, client)| Member {
+                id: id.clone(),
+                profile: client.profile.clone(),
+            })
+            .collect()
+    }
 
-                        },
+    fn is_member(&self, id: &String, room_id: &String) -> bool {
+        match self.clients.get(id) {
+            Some(Client { state: ClientState::VALIDATED(validated_room_id), .. }) => validated_room_id == room_id,
+            _ => false,
+        }
+    }
+
+    //Starts collecting receipts for a message ... the oldest tracked message is forgotten when full
+    fn track_receipts(&mut self, message_id: u64, room_id: String, sender_id: String) {
+        if self.receipt_order.len() >= MAX_TRACKED_RECEIPTS {
+            if let Some(oldest) = self.receipt_order.pop_front() {
+                self.receipts.remove(&oldest);
+            }
+        }
+        self.receipt_order.push_back(message_id);
+        self.receipts.insert(message_id, MessageReceipts {
+            room_id,
+            sender_id,
+            delivered: HashSet::new(),
+            read: HashSet::new(),
+        });
+    }
+
+    //Checks that a text may be posted to a room and works out when it expires ... sealed texts have no
+    //sender so they are posted as a non-admin. Returns the expiry time and whether the room tracks receipts
+    fn check_post(&self, room_id: &String, sender: Option<&String>, timestamp: u64, parent_id: Option<u64>, ttl: Option<u64>, epoch: Option<u64>) -> Result<(Option<u64>, bool), (ErrorCode, &'static str)> {
+        let (receipts_enabled, room_ttl) = match self.rooms.get(room_id) {
+            None => (false, None),
+            Some(room) => {
+                let sender_is_admin = match sender {
+                    None => false,
+                    Some(id) => room.is_admin(id),
+                };
+                if room.read_only && !sender_is_admin {
+                    return Err((ErrorCode::READ_ONLY, "Only admins can send messages in this room."));
+                }
+                //Texts encrypted under keys from before the latest join or leave are refused
+                if let Some(epoch) = epoch {
+                    if epoch != room.epoch {
+                        return Err((ErrorCode::STALE_EPOCH, "The message was encrypted for an old membership epoch."));
                     }
-                },
+                }
+                (room.receipts_enabled, room.ttl)
+            },
+        };
+        //The shorter of the message's and the room's time to live wins
+        let ttl = match (ttl, room_ttl) {
+            (Some(message_ttl), Some(room_ttl)) => Some(std::cmp::min(message_ttl, room_ttl)),
+            (message_ttl, room_ttl) => message_ttl.or(room_ttl),
+        };
+        let expires_at = ttl.map(|ttl| timestamp.saturating_add(ttl));
+        //Replies must refer to a message stored in the same room
+        if let Some(parent_id) = parent_id {
+            let parent_exists = match self.rooms.get(room_id) {
+                None => false,
+                Some(room) => room.history.get(parent_id).is_some(),
+            };
+            if !parent_exists {
+                return Err((ErrorCode::UNKNOWN_MESSAGE, "The message being replied to does not exist in this room."));
+            }
+        }
+        Ok((expires_at, receipts_enabled))
+    }
+
+    //Relays a client's text to its room and stores it ... returns the id given to the message
+    fn post_message(&mut self, mut msg: Message) -> Result<u64, (ErrorCode, &'static str)> {
+        let (expires_at, receipts_enabled) = self.check_post(&msg.room_id, Some(&msg.id), msg.timestamp, msg.parent_id, msg.ttl, msg.epoch)?;
+        //Expiry is worked out from the exact time ... only what is relayed and stored is rounded
+        let expires_at = expires_at.map(|expires_at| self.round_timestamp_up(expires_at));
+        msg.timestamp = self.round_timestamp(msg.timestamp);
+
+        let message_id = self.take_message_id();
+        self.broadcast_text(&msg.room_id, Text {
+            message_id,
+            id: msg.id.clone(),
+            message: msg.message.clone(),
+            timestamp: msg.timestamp,
+            parent_id: msg.parent_id,
+            expires_at,
+            epoch: msg.epoch,
+        });
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.history.push(StoredMessage {
+                message_id,
+                id: Some(msg.id.clone()),
+                message: msg.message,
+                timestamp: msg.timestamp,
+                parent_id: msg.parent_id,
+                expires_at,
+                edited: false,
+                reactions: HashMap::new(),
+            });
+        }
+        if receipts_enabled {
+            self.track_receipts(message_id, msg.room_id, msg.id);
+        }
+        Ok(message_id)
+    }
+
+    //Relays a sealed text to the room and stores it without a sender ... receipts are not tracked
+    //since they would tie the text back to its sender
+    fn post_sealed_message(&mut self, mut msg: SealedMessage) -> Result<u64, (ErrorCode, &'static str)> {
+        let (expires_at, _) = self.check_post(&msg.room_id, None, msg.timestamp, msg.parent_id, msg.ttl, msg.epoch)?;
+        let expires_at = expires_at.map(|expires_at| self.round_timestamp_up(expires_at));
+        msg.timestamp = self.round_timestamp(msg.timestamp);
+
+        let message_id = self.take_message_id();
+        let text = SealedText {
+            message_id,
+            message: msg.message.clone(),
+            timestamp: msg.timestamp,
+            parent_id: msg.parent_id,
+            expires_at,
+            epoch: msg.epoch,
+        };
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            for client in room.members.iter() {
+                client.do_send(text.clone());
             }
+            room.history.push(StoredMessage {
+                message_id,
+                id: None,
+                message: msg.message,
+                timestamp: msg.timestamp,
+                parent_id: msg.parent_id,
+                expires_at,
+                edited: false,
+                reactions: HashMap::new(),
+            });
+        }
+        Ok(message_id)
+    }
+
+    //Sends dummy frames to members of rooms with cover traffic on ... each member gets one with
+    //probability tick/interval so frames arrive at random times, up to the server wide caps
+    fn send_cover_traffic(&mut self) {
+        let mut rng = rand::thread_rng();
+        let mut frames = 0;
+        let mut bytes = 0;
+        let mut dummies = Vec::new();
+        for room in self.rooms.values() {
+            let interval = match room.cover_interval {
+                None => continue,
+                Some(interval) => interval,
+            };
+            let probability = COVER_TICK.as_secs() as f64 / interval as f64;
+            for client in room.members.iter() {
+                if rng.gen::<f64>() >= probability {
+                    continue;
+                }
+                //Dummies are shaped like a text in the room ... peers fail to decrypt and drop them
+                let buckets = &room.policy.padding_buckets;
+                let ciphertext_length = if buckets.is_empty() {
+                    DEFAULT_COVER_LENGTH
+                } else {
+                    buckets[rng.gen::<usize>() % buckets.len()]
+                };
+                let length = match envelope::envelope_length(ciphertext_length) {
+                    None => continue,
+                    Some(length) => length,
+                };
+                if frames + 1 > self.cover_traffic.max_frames_per_second || bytes + length > self.cover_traffic.max_bytes_per_second {
+                    break;
+                }
+                frames += 1;
+                bytes += length;
+                dummies.push((client.clone(), ciphertext_length));
+            }
+        }
+
+        for (client, ciphertext_length) in dummies {
+            let random_field = |length: usize, rng: &mut ThreadRng| {
+                let mut field = vec![0u8; length];
+                rng.fill(&mut field[..]);
+                base64::encode(&field)
+            };
+            let sender_id: [u8; 8] = rng.gen();
+            let message = format!("{}|{}|{}",
+                                  random_field(32, &mut rng),
+                                  random_field(ciphertext_length, &mut rng),
+                                  random_field(32, &mut rng));
+            let message_id = self.take_message_id();
+            client.do_send(Cover(Text {
+                message_id,
+                id: hex::encode(sender_id),
+                message,
+                timestamp: self.round_timestamp(get_unix_time()),
+                parent_id: None,
+                expires_at: None,
+                epoch: None,
+            }));
+        }
+    }
+
+    //Sends the room's current rules to every member's session
+    fn broadcast_policy(&self, room_id: &String) {
+        if let Some(room) = self.rooms.get(room_id) {
+            for client in room.members.iter() {
+                client.do_send(room.policy.clone());
+            }
+        }
+    }
+
+    //Sends an error to a client
+    fn send_error(&self, id: &String, code: ErrorCode, detail: &str) {
+        self.send_text_error(id, None, code, detail);
+    }
+
+    //Refuses a text ... the error echoes the key the client attached to the text so it knows which one failed
+    fn send_text_error(&self, id: &String, idempotency_key: Option<String>, code: ErrorCode, detail: &str) {
+        if let Some(client) = self.clients.get(id) {
+            client.client_addr.do_send(ServerPacket::Error {
+                idempotency_key,
+                code,
+                detail: detail.to_string(),
+            });
+        }
+    }
+
+    //Tells a client the current epoch of its room's group ... sent after refusing a handshake for another epoch
+    fn send_group_epoch(&self, id: &String, room_id: &String) {
+        if let (Some(client), Some(room)) = (self.clients.get(id), self.rooms.get(room_id)) {
+            client.client_addr.do_send(ServerPacket::GroupEpoch {
+                epoch: room.group_epoch,
+                committer: room.group_committer.clone(),
+            });
+        }
+    }
+
+    //Sends an event to every client in a room
+    fn broadcast_packet(&self, room_id: &String, packet: ServerPacket) {
+        if let Some(room) = self.rooms.get(room_id) {
+            for client in room.members.iter() {
+                client.do_send(packet.clone());
+            }
+        }
+    }
+
+    //Takes a text out of the schedule
+    fn unschedule(&mut self, schedule_id: u64) -> Option<ScheduledMessage> {
+        let scheduled = self.scheduled.remove(&schedule_id)?;
+        self.schedule_queue.remove(&(scheduled.deliver_at, schedule_id));
+        Some(scheduled)
+    }
+
+    //Checks that a client may edit or delete a message ... only the sender and the room's admins can
+    fn authorize_change(&self, id: &String, room_id: &String, message_id: u64) -> Result<(), (ErrorCode, &'static str)> {
+        let room = match self.rooms.get(room_id) {
+            None => return Err((ErrorCode::UNKNOWN_MESSAGE, "The message does not exist.")),
+            Some(room) => room,
+        };
+        match room.history.get(message_id) {
+            None => Err((ErrorCode::UNKNOWN_MESSAGE, "The message does not exist or is no longer stored.")),
+            Some(stored) => {
+                if stored.id.as_ref() == Some(id) || room.is_admin(id) {
+                    Ok(())
+                } else {
+                    Err((ErrorCode::NOT_PERMITTED, "Only the sender or a room admin can change this message."))
+                }
+            },
+        }
+    }
+
+    //Forgets every receipt collected for messages in a room
+    fn drop_receipts(&mut self, room_id: &String) {
+        let receipts = &mut self.receipts;
+        receipts.retain(|_, receipt| &receipt.room_id != room_id);
+        self.receipt_order.retain(|message_id| receipts.contains_key(message_id));
+    }
+
+}
+
+impl Actor for ChatServer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        //Forget idempotency keys once they fall out of the window
+        ctx.run_interval(IDEMPOTENCY_WINDOW, |actor, _context| {
+            actor.idempotency_keys.retain(|_, (_, sent)| Instant::now().duration_since(*sent) < IDEMPOTENCY_WINDOW);
+        });
+
+        //Purge disappearing messages and tell the rooms which messages are gone
+        ctx.run_interval(TTL_SWEEP_INTERVAL, |actor, _context| {
+            let now = get_unix_time();
+            for room in actor.rooms.values_mut() {
+                let message_ids = room.history.expire(now);
+                if message_ids.is_empty() {
+                    continue;
+                }
+                for client in room.members.iter() {
+                    client.do_send(ServerPacket::Expired {
+                        message_ids: message_ids.clone(),
+                    });
+                }
+            }
+        });
+
+        ctx.run_interval(COVER_TICK, |actor, _context| {
+            actor.send_cover_traffic();
+        });
+
+        //Deliver scheduled texts that are due
+        ctx.run_interval(TTL_SWEEP_INTERVAL, |actor, _context| {
+            let now = get_unix_time();
+            let due: Vec<u64> = actor.schedule_queue.range(..(now.saturating_add(1), 0))
+                .map(|(_, schedule_id)| *schedule_id)
+                .collect();
+            for schedule_id in due {
+                let scheduled = match actor.unschedule(schedule_id) {
+                    None => continue,
+                    Some(scheduled) => scheduled,
+                };
+                //The room may have become read-only since the text was scheduled ... let the sender know
+                let sender_id = scheduled.id.clone();
+                let posted = actor.post_message(Message {
+                    id: scheduled.id,
+                    room_id: scheduled.room_id,
+                    message: scheduled.message,
+                    timestamp: now,
+                    parent_id: None,
+                    ttl: scheduled.ttl,
+                    epoch: None,
+                    idempotency_key: None,
+                });
+                if let Err((code, detail)) = posted {
+                    actor.send_error(&sender_id, code, detail);
+                }
+            }
+        });
+    }
+}
+
+//Client is attempting to join a room
+impl Handler<Register> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, mut registration: Register, ctx: &mut Self::Context) -> Self::Result {
+        //Room names chosen by people are refused when the server only accepts blinded room ids
+        if self.blinded_room_ids && !is_blinded_room_id(&registration.room_id) {
+            registration.addr.do_send(ServerPacket::Error {
+                idempotency_key: None,
+                code: ErrorCode::INVALID,
+                detail: format!("Room ids must be {} lowercase hex characters derived from the room secret.", BLINDED_ROOM_ID_LENGTH),
+            });
+            return;
+        }
+        //Oversized profiles are dropped rather than refusing the registration
+        registration.profile = registration.profile.filter(|profile| profile.len() <= MAX_PROFILE_LENGTH);
+        //Locked rooms refuse newcomers outright ... the client stays where it is
+        let (locked, removed) = match self.rooms.get(&registration.room_id) {
+            None => (false, false),
+            Some(room) => (room.locked, room.removed_ids.contains(&registration.id)),
+        };
+        if locked {
+            registration.addr.do_send(ServerPacket::Error {
+                idempotency_key: None,
+                code: ErrorCode::ROOM_LOCKED,
+                detail: "The room is locked and is not admitting anyone.".to_string(),
+            });
+            return;
+        }
+        if removed {
+            registration.addr.do_send(ServerPacket::Error {
+                idempotency_key: None,
+                code: ErrorCode::NOT_PERMITTED,
+                detail: "You have been voted out of this room.".to_string(),
+            });
+            return;
+        }
+        //Handle case where client switches to another room ... send a disconnect signal
+        self.handle(Disconnect {
+            id: registration.id.clone(),
+            full_disconnect: false,
+        }, ctx);
+
+
+        let awaiting_validation = match self.rooms.get_mut(&registration.room_id) {
+            //Room doesn't exist...make a new one
+            None => false,
+            //Room exists but is empty ... validate and register the connecting client
+            //Clients a member has pre-authorized are let in without asking the room
+            Some(room) => !room.members.is_empty() && !room.take_preauthorization(&registration.id, &registration.invite_code),
+        };
+
+        if !awaiting_validation {
+            //New and emptied rooms can be claimed by whoever registers first
+            let room = self.rooms.entry(registration.room_id.clone()).or_insert_with(Room::new);
+            if registration.claim_ownership && room.members.is_empty() && room.owner.is_none() {
+                room.owner = Some(registration.id.clone());
+            }
+            self.admit(registration.id, registration.room_id, registration.addr, registration.profile);
+        } else {
+            //Send a validation request to every client in the room
+            if let Some(room) = self.rooms.get(&registration.room_id) {
+                for client in room.members.iter() {
+                    client.do_send(ValidationRequest {
+                        room_id: registration.room_id.clone(),
+                        id: registration.id.clone(),
+                        validation: registration.validation.clone(),
+                        accept: false,
+                    });
+                }
+            }
+            //Register the client but do not add into room
+            self.clients.insert(registration.id.clone(), Client {
+                room_id: registration.room_id.clone(),
+                state: ClientState::AWAITING_VALIDATION,
+                client_addr: registration.addr.clone(),
+                profile: registration.profile.clone(),
+            });
+            registration.addr.do_send(ClientState::AWAITING_VALIDATION);
+        }
+        self.log_client_count();
+    }
+}
+
+impl Handler<ValidationRequest> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ValidationRequest, _ctx: &mut Self::Context) -> Self::Result {
+        //Don't validate client with peers who don't accept
+        if !msg.accept {
+            return;
+        }
+        let (client_addr, profile) = match self.clients.get(&msg.id) {
+            //Fail silently for bogus validation requests that attempt to validate a non-existent user
+            None => return,
+            Some(client) => {
+                //Stale acceptances for clients that have left or cancelled are ignored
+                let awaiting = matches!(client.state, ClientState::AWAITING_VALIDATION);
+                if !awaiting || client.room_id != msg.room_id {
+                    return;
+                }
+                (client.client_addr.clone(), client.profile.clone())
+            },
+        };
+        match self.rooms.get(&msg.room_id) {
+            //Rooms locked after the client knocked don't admit it either
+            Some(room) if !room.locked => {
+                //All sanity checks passed ... validate the client
+                self.admit(msg.id, msg.room_id, client_addr, profile);
+            },
+            _ => {},
         }
     }
 }
@@ -214,7 +1300,865 @@ impl Handler<Message> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Message, _: &mut Self::Context) -> Self::Result {
-        self.broadcast_message(msg.room_id, msg.id, msg.message, msg.timestamp);
+        let sender_addr = match self.clients.get(&msg.id) {
+            None => return,
+            Some(client) => client.client_addr.clone(),
+        };
+
+        //A retried message that was already posted is only acknowledged again
+        if let Some(key) = &msg.idempotency_key {
+            if let Some((message_id, sent)) = self.idempotency_keys.get(&(msg.id.clone(), key.clone())) {
+                if Instant::now().duration_since(*sent) < IDEMPOTENCY_WINDOW {
+                    sender_addr.do_send(ServerPacket::Ack {
+                        idempotency_key: msg.idempotency_key.clone(),
+                        message_id: *message_id,
+                    });
+                    return;
+                }
+            }
+        }
+
+        let id = msg.id.clone();
+        let idempotency_key = msg.idempotency_key.clone();
+        let message_id = match self.post_message(msg) {
+            Err((code, detail)) => {
+                self.send_text_error(&id, idempotency_key, code, detail);
+                return;
+            },
+            Ok(message_id) => message_id,
+        };
+
+        if let Some(key) = &idempotency_key {
+            self.idempotency_keys.insert((id, key.clone()), (message_id, Instant::now()));
+        }
+        sender_addr.do_send(ServerPacket::Ack {
+            idempotency_key,
+            message_id,
+        });
+    }
+}
+
+impl Handler<SealedMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SealedMessage, _: &mut Self::Context) -> Self::Result {
+        //Only sockets that are members of the room may post to it
+        let is_member = match self.rooms.get(&msg.room_id) {
+            None => false,
+            Some(room) => room.members.contains(&msg.addr),
+        };
+        let sender_addr = msg.addr.clone();
+        if !is_member {
+            sender_addr.do_send(ServerPacket::Error {
+                idempotency_key: None,
+                code: ErrorCode::NOT_PERMITTED,
+                detail: "You must be validated into the room to send a sealed text.".to_string(),
+            });
+            return;
+        }
+
+        match self.post_sealed_message(msg) {
+            Err((code, detail)) => {
+                sender_addr.do_send(ServerPacket::Error {
+                    idempotency_key: None,
+                    code,
+                    detail: detail.to_string(),
+                });
+            },
+            Ok(message_id) => {
+                sender_addr.do_send(ServerPacket::Ack {
+                    idempotency_key: None,
+                    message_id,
+                });
+            },
+        }
+    }
+}
+
+impl Handler<Edit> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Edit, _: &mut Self::Context) -> Self::Result {
+        if let Err((code, detail)) = self.authorize_change(&msg.id, &msg.room_id, msg.message_id) {
+            self.send_error(&msg.id, code, detail);
+            return;
+        }
+        //Replace the old ciphertext so the server no longer holds it
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            if let Some(stored) = room.history.get_mut(msg.message_id) {
+                stored.message = msg.message.clone();
+                stored.edited = true;
+            }
+        }
+        self.broadcast_packet(&msg.room_id, ServerPacket::Edited {
+            message_id: msg.message_id,
+            id: msg.id,
+            message: msg.message,
+            timestamp: self.round_timestamp(get_unix_time()),
+        });
+    }
+}
+
+impl Handler<Delete> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Delete, _: &mut Self::Context) -> Self::Result {
+        if let Err((code, detail)) = self.authorize_change(&msg.id, &msg.room_id, msg.message_id) {
+            self.send_error(&msg.id, code, detail);
+            return;
+        }
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            room.history.remove(msg.message_id);
+        }
+        self.broadcast_packet(&msg.room_id, ServerPacket::Deleted {
+            message_id: msg.message_id,
+            id: msg.id,
+        });
+    }
+}
+
+impl Handler<Schedule> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Schedule, _: &mut Self::Context) -> Self::Result {
+        let read_only = match self.rooms.get(&msg.room_id) {
+            None => false,
+            Some(room) => room.read_only && !room.is_admin(&msg.id),
+        };
+        if read_only {
+            self.send_error(&msg.id, ErrorCode::READ_ONLY, "Only admins can send messages in this room.");
+            return;
+        }
+        let now = get_unix_time();
+        if msg.deliver_at < now || msg.deliver_at - now > MAX_SCHEDULE_HORIZON {
+            self.send_error(&msg.id, ErrorCode::INVALID, "Texts can only be scheduled up to a week ahead.");
+            return;
+        }
+        let pending = self.scheduled.values().filter(|scheduled| scheduled.id == msg.id).count();
+        let pending_in_room = self.scheduled.values().filter(|scheduled| scheduled.room_id == msg.room_id).count();
+        if pending >= MAX_SCHEDULED_PER_CLIENT || pending_in_room >= MAX_SCHEDULED_PER_ROOM || self.scheduled.len() >= MAX_SCHEDULED {
+            self.send_error(&msg.id, ErrorCode::LIMIT_REACHED, "Too many scheduled messages are pending.");
+            return;
+        }
+        let schedule_id = self.next_schedule_id;
+        self.next_schedule_id += 1;
+        let scheduled = ScheduledMessage {
+            schedule_id,
+            id: msg.id.clone(),
+            room_id: msg.room_id,
+            message: msg.message,
+            deliver_at: msg.deliver_at,
+            ttl: msg.ttl,
+        };
+        self.scheduled.insert(schedule_id, scheduled.clone());
+        self.schedule_queue.insert((scheduled.deliver_at, schedule_id));
+        if let Some(client) = self.clients.get(&msg.id) {
+            client.client_addr.do_send(ServerPacket::Scheduled {
+                messages: vec![scheduled],
+            });
+        }
+    }
+}
+
+impl Handler<ListScheduled> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ListScheduled, _: &mut Self::Context) -> Self::Result {
+        let mut messages: Vec<ScheduledMessage> = self.scheduled.values()
+            .filter(|scheduled| scheduled.id == msg.id)
+            .cloned()
+            .collect();
+        messages.sort_by_key(|scheduled| scheduled.deliver_at);
+        if let Some(client) = self.clients.get(&msg.id) {
+            client.client_addr.do_send(ServerPacket::Scheduled {
+                messages,
+            });
+        }
+    }
+}
+
+impl Handler<CancelScheduled> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: CancelScheduled, _: &mut Self::Context) -> Self::Result {
+        //Only the sender can cancel its scheduled texts
+        match self.scheduled.get(&msg.schedule_id) {
+            Some(scheduled) if scheduled.id == msg.id => {
+                self.unschedule(msg.schedule_id);
+                if let Some(client) = self.clients.get(&msg.id) {
+                    client.client_addr.do_send(ServerPacket::ScheduleCancelled {
+                        schedule_id: msg.schedule_id,
+                    });
+                }
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::UNKNOWN_MESSAGE, "There is no such scheduled message.");
+            },
+        }
+    }
+}
+
+impl Handler<ThreadQuery> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ThreadQuery, _: &mut Self::Context) -> Self::Result {
+        let messages = match self.rooms.get(&msg.room_id) {
+            None => Vec::new(),
+            Some(room) => room.history.thread(msg.message_id),
+        };
+        if messages.is_empty() {
+            self.send_error(&msg.id, ErrorCode::UNKNOWN_MESSAGE, "The thread does not exist or is no longer stored.");
+            return;
+        }
+        if let Some(client) = self.clients.get(&msg.id) {
+            client.client_addr.do_send(ServerPacket::Thread {
+                message_id: msg.message_id,
+                messages,
+            });
+        }
+    }
+}
+
+impl Handler<React> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: React, _: &mut Self::Context) -> Self::Result {
+        //Reactions are aggregated on the stored message ... messages that are no longer stored can't be reacted to
+        let stored = match self.rooms.get_mut(&msg.room_id) {
+            None => None,
+            Some(room) => room.history.get_mut(msg.message_id),
+        };
+        match stored {
+            None => {
+                self.send_error(&msg.id, ErrorCode::UNKNOWN_MESSAGE, "The message does not exist or is no longer stored.");
+                return;
+            },
+            Some(stored) => {
+                match &msg.reaction {
+                    Some(reaction) => {
+                        stored.reactions.insert(msg.id.clone(), reaction.clone());
+                    },
+                    None => {
+                        stored.reactions.remove(&msg.id);
+                    },
+                }
+            },
+        }
+        self.broadcast_packet(&msg.room_id, ServerPacket::Reaction {
+            message_id: msg.message_id,
+            id: msg.id,
+            reaction: msg.reaction,
+        });
+    }
+}
+
+impl Handler<SetAdmin> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetAdmin, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.owner.as_ref() == Some(&msg.id) => {
+                if msg.admin {
+                    room.admins.insert(msg.target_id.clone());
+                } else {
+                    room.admins.remove(&msg.target_id);
+                }
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only the owner of the room can change its admins.");
+                return;
+            },
+        }
+        self.broadcast_message(msg.room_id,
+                               "Server".to_string(),
+                               format!("User {} is {} an admin", msg.target_id, if msg.admin { "now" } else { "no longer" }),
+                               get_unix_time());
+    }
+}
+
+impl Handler<Receipt> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Receipt, _: &mut Self::Context) -> Self::Result {
+        let receipt = match self.receipts.get_mut(&msg.message_id) {
+            //Receipts are off for the room or the message is too old
+            None => return,
+            Some(receipt) => receipt,
+        };
+        //Only members of the room the message was sent in may acknowledge it
+        match self.clients.get(&msg.id) {
+            Some(Client { state: ClientState::VALIDATED(room_id), .. }) if room_id == &receipt.room_id => {},
+            _ => return,
+        }
+        if msg.id == receipt.sender_id {
+            return;
+        }
+
+        //Reading a message implies it was delivered
+        let mut changed = receipt.delivered.insert(msg.id.clone());
+        if msg.read {
+            changed |= receipt.read.insert(msg.id);
+        }
+
+        if changed {
+            if let Some(sender) = self.clients.get(&receipt.sender_id) {
+                sender.client_addr.do_send(ServerPacket::Receipt {
+                    message_id: msg.message_id,
+                    delivered: receipt.delivered.iter().cloned().collect(),
+                    read: receipt.read.iter().cloned().collect(),
+                });
+            }
+        }
+    }
+}
+
+impl Handler<SetReceipts> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetReceipts, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                if room.receipts_enabled == msg.enabled {
+                    return;
+                }
+                room.receipts_enabled = msg.enabled;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can turn receipts on or off in this room.");
+                return;
+            },
+        }
+        if !msg.enabled {
+            self.drop_receipts(&msg.room_id);
+        }
+        self.broadcast_message(msg.room_id,
+                               "Server".to_string(),
+                               format!("User {} has turned receipts {}", msg.id, if msg.enabled { "on" } else { "off" }),
+                               get_unix_time());
+    }
+}
+
+impl Handler<Signal> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Self::Context) -> Self::Result {
+        let sender_addr = match self.clients.get(&msg.id) {
+            None => return,
+            Some(client) => client.client_addr.clone(),
+        };
+        match self.rooms.get(&msg.room_id) {
+            None => {},
+            Some(room) => {
+                for client in room.members.iter() {
+                    if client == &sender_addr {
+                        continue;
+                    }
+                    //Signals are best effort ... drop them instead of queueing when a session is backed up
+                    let _ = client.try_send(ServerPacket::Signal {
+                        id: msg.id.clone(),
+                        signal: msg.signal.clone(),
+                    });
+                }
+            },
+        }
+    }
+}
+
+impl Handler<SetRoomTtl> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRoomTtl, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                room.ttl = msg.ttl;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change how long messages live in this room.");
+                return;
+            },
+        }
+        let announcement = match msg.ttl {
+            None => format!("User {} has turned off disappearing messages", msg.id),
+            Some(ttl) => format!("User {} has set messages to disappear after {} seconds", msg.id, ttl),
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
+    }
+}
+
+impl Handler<SetMetadata> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetMetadata, _: &mut Self::Context) -> Self::Result {
+        if msg.metadata.len() > MAX_METADATA_LENGTH {
+            self.send_error(&msg.id, ErrorCode::TOO_LARGE, "The room metadata is too large.");
+            return;
+        }
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => room,
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change the metadata of this room.");
+                return;
+            },
+        };
+        //Compare and swap ... the client must have seen the latest metadata to replace it
+        if room.metadata_version != msg.version {
+            let current = ServerPacket::Metadata {
+                version: room.metadata_version,
+                metadata: room.metadata.clone(),
+            };
+            self.send_error(&msg.id, ErrorCode::VERSION_CONFLICT, "The room metadata has changed since it was last seen.");
+            if let Some(client) = self.clients.get(&msg.id) {
+                client.client_addr.do_send(current);
+            }
+            return;
+        }
+        room.metadata = msg.metadata;
+        room.metadata_version += 1;
+        let update = ServerPacket::Metadata {
+            version: room.metadata_version,
+            metadata: room.metadata.clone(),
+        };
+        self.broadcast_packet(&msg.room_id, update);
+    }
+}
+
+impl Handler<SetProfile> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetProfile, _: &mut Self::Context) -> Self::Result {
+        let too_large = match &msg.profile {
+            None => false,
+            Some(profile) => profile.len() > MAX_PROFILE_LENGTH,
+        };
+        if too_large {
+            self.send_error(&msg.id, ErrorCode::TOO_LARGE, "The profile is too large.");
+            return;
+        }
+        match self.clients.get_mut(&msg.id) {
+            None => return,
+            Some(client) => {
+                client.profile = msg.profile.clone();
+            },
+        }
+        self.broadcast_packet(&msg.room_id, ServerPacket::Profile {
+            member: Member {
+                id: msg.id,
+                profile: msg.profile,
+            },
+        });
+    }
+}
+
+impl Handler<SetReadOnly> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetReadOnly, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.owner.as_ref() == Some(&msg.id) => {
+                if room.read_only == msg.read_only {
+                    return;
+                }
+                room.read_only = msg.read_only;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only the owner of the room can change its announcement mode.");
+                return;
+            },
+        }
+        let announcement = if msg.read_only {
+            "This room is now read-only ... only admins can send messages"
+        } else {
+            "This room is no longer read-only ... everyone can send messages"
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement.to_string(), get_unix_time());
+    }
+}
+
+impl Handler<SetLocked> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetLocked, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.is_admin(&msg.id) => {
+                if room.locked == msg.locked {
+                    return;
+                }
+                room.locked = msg.locked;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only the owner or an admin of the room can lock it.");
+                return;
+            },
+        }
+        let announcement = if msg.locked {
+            format!("User {} has locked the room ... no one else can join", msg.id)
+        } else {
+            format!("User {} has unlocked the room", msg.id)
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
+    }
+}
+
+impl Handler<PreAuthorize> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PreAuthorize, _: &mut Self::Context) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            None => return,
+            Some(room) => room,
+        };
+        if room.preauthorized_ids.len() + room.invite_codes.len() >= MAX_PREAUTHORIZATIONS {
+            self.send_error(&msg.id, ErrorCode::LIMIT_REACHED, "The room has too many unused pre-authorizations.");
+            return;
+        }
+        //Invite codes are never announced ... only the member that made one should hand it out
+        let announcement = match msg.admission {
+            Admission::Identity(target_id) => {
+                let announcement = format!("User {} has pre-approved {}", msg.id, target_id);
+                room.preauthorized_ids.insert(target_id);
+                announcement
+            },
+            Admission::InviteCode(code) => {
+                room.invite_codes.insert(code);
+                format!("User {} has created an invite", msg.id)
+            },
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
+    }
+}
+
+impl Handler<VoteRemove> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: VoteRemove, ctx: &mut Self::Context) -> Self::Result {
+        if !self.is_member(&msg.target_id, &msg.room_id) || msg.target_id == msg.id {
+            self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "You can only vote to remove another member of the room.");
+            return;
+        }
+        //Only members still in the room count towards the quorum
+        let members: HashSet<String> = self.roster(&msg.room_id).into_iter().map(|member| member.id).collect();
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            None => return,
+            Some(room) => room,
+        };
+        if room.owner.as_ref() == Some(&msg.target_id) {
+            self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "The owner of the room can't be voted out.");
+            return;
+        }
+
+        let voters = room.removal_votes.entry(msg.target_id.clone()).or_insert_with(HashSet::new);
+        voters.insert(msg.id.clone());
+        voters.retain(|voter| members.contains(voter));
+        let votes = voters.len();
+        let required = removal_votes_required(members.len(), room.removal_quorum);
+
+        if votes < required {
+            self.broadcast_packet(&msg.room_id, ServerPacket::RemovalVote {
+                id: msg.target_id,
+                votes,
+                required,
+            });
+            return;
+        }
+
+        //Quorum reached ... evict the member and keep its connection from coming back
+        room.removal_votes.remove(&msg.target_id);
+        room.removed_ids.insert(msg.target_id.clone());
+        room.preauthorized_ids.remove(&msg.target_id);
+        let target_id = msg.target_id.clone();
+        let target_schedule_ids: Vec<u64> = self.scheduled.values()
+            .filter(|scheduled| scheduled.id == target_id)
+            .map(|scheduled| scheduled.schedule_id)
+            .collect();
+        for schedule_id in target_schedule_ids {
+            self.unschedule(schedule_id);
+        }
+        //Tell the evicted member why it is leaving before it stops hearing from the room
+        let removed = ServerPacket::Removed {
+            id: msg.target_id.clone(),
+            votes,
+        };
+        if let Some(client) = self.clients.get(&msg.target_id) {
+            client.client_addr.do_send(removed.clone());
+        }
+        self.handle(Leave {
+            id: msg.target_id.clone(),
+        }, ctx);
+        self.broadcast_packet(&msg.room_id, removed);
+        println!("{} was voted out of {}", self.user_label(&msg.target_id), self.room_label(&msg.room_id));
+    }
+}
+
+impl Handler<SetRemovalQuorum> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRemovalQuorum, _: &mut Self::Context) -> Self::Result {
+        if msg.quorum == 0 || msg.quorum > 100 {
+            self.send_error(&msg.id, ErrorCode::INVALID, "The quorum must be a percentage between 1 and 100.");
+            return;
+        }
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                room.removal_quorum = msg.quorum;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change the removal quorum of this room.");
+                return;
+            },
+        }
+        self.broadcast_message(msg.room_id,
+                               "Server".to_string(),
+                               format!("User {} has set the removal quorum to {}% of members", msg.id, msg.quorum),
+                               get_unix_time());
+    }
+}
+
+impl Handler<Rekey> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Rekey, _: &mut Self::Context) -> Self::Result {
+        let sender_addr = match self.clients.get(&msg.id) {
+            None => return,
+            Some(client) => client.client_addr.clone(),
+        };
+        let room = match self.rooms.get(&msg.room_id) {
+            None => return,
+            Some(room) => room,
+        };
+        if msg.epoch != room.epoch {
+            self.send_error(&msg.id, ErrorCode::STALE_EPOCH, "Rekey messages must be for the current membership epoch.");
+            return;
+        }
+        for client in room.members.iter() {
+            if client == &sender_addr {
+                continue;
+            }
+            client.do_send(ServerPacket::Rekey {
+                id: msg.id.clone(),
+                epoch: msg.epoch,
+                payload: msg.payload.clone(),
+            });
+        }
+    }
+}
+
+impl Handler<HandshakeMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: HandshakeMessage, _: &mut Self::Context) -> Self::Result {
+        let room = match self.rooms.get_mut(&msg.room_id) {
+            None => return,
+            Some(room) => room,
+        };
+        match msg.handshake {
+            Handshake::Proposal { epoch, payload } => {
+                if epoch != room.group_epoch {
+                    self.send_error(&msg.id, ErrorCode::STALE_EPOCH, "Proposals must be for the current group epoch.");
+                    self.send_group_epoch(&msg.id, &msg.room_id);
+                    return;
+                }
+                self.broadcast_packet(&msg.room_id, ServerPacket::Proposal {
+                    id: msg.id,
+                    epoch,
+                    payload,
+                });
+            },
+            Handshake::Commit { epoch, payload } => {
+                //The first commit for an epoch wins ... commits that lost the race are late
+                if epoch != room.group_epoch {
+                    self.send_error(&msg.id, ErrorCode::STALE_EPOCH, "Another commit has already been accepted for this group epoch.");
+                    self.send_group_epoch(&msg.id, &msg.room_id);
+                    return;
+                }
+                room.group_epoch += 1;
+                room.group_committer = Some(msg.id.clone());
+                //Sender keys queued for the old epoch are useless now
+                room.pending_sender_keys.clear();
+                //Everyone including the committer learns the commit was accepted and the new epoch it starts
+                let new_epoch = room.group_epoch;
+                self.broadcast_packet(&msg.room_id, ServerPacket::Commit {
+                    id: msg.id,
+                    epoch: new_epoch,
+                    payload,
+                });
+            },
+            Handshake::Welcome { epoch, recipients, payload } => {
+                if epoch != room.group_epoch || room.group_committer.as_ref() != Some(&msg.id) {
+                    self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only the client whose commit started the current group epoch can welcome joiners.");
+                    return;
+                }
+                //Welcomes only go to the named joiners ... and only if they are joining this room
+                for recipient in recipients.iter() {
+                    let client = match self.clients.get(recipient) {
+                        None => continue,
+                        Some(client) => client,
+                    };
+                    //Clients that left keep their last room id ... their state tells whether they are still joining
+                    let joining = match &client.state {
+                        ClientState::AWAITING_VALIDATION => client.room_id == msg.room_id,
+                        ClientState::VALIDATED(room_id) => room_id == &msg.room_id,
+                        _ => false,
+                    };
+                    if joining {
+                        client.client_addr.do_send(ServerPacket::Welcome {
+                            id: msg.id.clone(),
+                            epoch,
+                            payload: payload.clone(),
+                        });
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Handler<SenderKeys> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SenderKeys, _: &mut Self::Context) -> Self::Result {
+        let current_epoch = match self.rooms.get(&msg.room_id) {
+            None => return,
+            Some(room) => room.group_epoch,
+        };
+        if msg.epoch != current_epoch {
+            self.send_error(&msg.id, ErrorCode::STALE_EPOCH, "Sender keys must be for the current group epoch.");
+            self.send_group_epoch(&msg.id, &msg.room_id);
+            return;
+        }
+
+        //Each recipient only gets the bundle encrypted for it
+        let sender_id = msg.id.clone();
+        let mut queued = Vec::new();
+        for (recipient, bundle) in msg.bundles {
+            if recipient == sender_id {
+                continue;
+            }
+            if self.is_member(&recipient, &msg.room_id) {
+                if let Some(client) = self.clients.get(&recipient) {
+                    client.client_addr.do_send(ServerPacket::SenderKey {
+                        id: sender_id.clone(),
+                        epoch: msg.epoch,
+                        bundle,
+                    });
+                }
+            } else {
+                queued.push((recipient, bundle));
+            }
+        }
+
+        //Recipients that haven't joined yet get their bundle when they do ... as long as the epoch lasts
+        let mut dropped = false;
+        if let Some(room) = self.rooms.get_mut(&msg.room_id) {
+            let mut pending_count: usize = room.pending_sender_keys.values().map(|pending| pending.len()).sum();
+            let mut sender_count = room.pending_sender_keys.values()
+                .flat_map(|pending| pending.iter())
+                .filter(|sender_key| sender_key.id == sender_id)
+                .count();
+            for (recipient, bundle) in queued {
+                let pending = room.pending_sender_keys.entry(recipient).or_insert_with(Vec::new);
+                //A newer bundle from the same sender replaces the old one
+                let replaces = pending.iter().any(|sender_key| sender_key.id == sender_id);
+                if !replaces && (pending_count >= MAX_PENDING_SENDER_KEYS || sender_count >= MAX_PENDING_SENDER_KEYS_PER_SENDER) {
+                    dropped = true;
+                    continue;
+                }
+                pending.retain(|sender_key| sender_key.id != sender_id);
+                pending.push(PendingSenderKey {
+                    id: sender_id.clone(),
+                    epoch: msg.epoch,
+                    bundle,
+                });
+                if !replaces {
+                    pending_count += 1;
+                    sender_count += 1;
+                }
+            }
+            //Recipients whose bundles were all refused don't need an entry
+            room.pending_sender_keys.retain(|_, pending| !pending.is_empty());
+        }
+        if dropped {
+            self.send_error(&sender_id, ErrorCode::LIMIT_REACHED, "Too many sender keys are waiting for clients to join ... some were not queued.");
+        }
+    }
+}
+
+impl Handler<SetStrictMode> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetStrictMode, _: &mut Self::Context) -> Self::Result {
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                if room.policy.strict_envelopes == msg.strict {
+                    return;
+                }
+                room.policy.strict_envelopes = msg.strict;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change the strict mode of this room.");
+                return;
+            },
+        }
+        self.broadcast_policy(&msg.room_id);
+        let announcement = if msg.strict {
+            format!("User {} has turned on strict mode ... only encrypted messages are accepted", msg.id)
+        } else {
+            format!("User {} has turned off strict mode", msg.id)
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
+    }
+}
+
+impl Handler<SetPadding> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPadding, _: &mut Self::Context) -> Self::Result {
+        let mut buckets = msg.buckets;
+        buckets.sort();
+        buckets.dedup();
+        let valid = buckets.len() <= MAX_PADDING_BUCKETS
+            && buckets.iter().all(|bucket| *bucket > 0 && *bucket <= MAX_PADDING_BUCKET && bucket % envelope::BLOCK_SIZE == 0);
+        if !valid {
+            self.send_error(&msg.id, ErrorCode::INVALID, "Padding buckets must be positive multiples of the AES block size of at most 32768 bytes.");
+            return;
+        }
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                room.policy.padding_buckets = buckets.clone();
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change the padding of this room.");
+                return;
+            },
+        }
+        self.broadcast_policy(&msg.room_id);
+        let announcement = if buckets.is_empty() {
+            format!("User {} has turned off message padding", msg.id)
+        } else {
+            format!("User {} has set message padding to {:?} bytes", msg.id, buckets)
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
+    }
+}
+
+impl Handler<SetCoverTraffic> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetCoverTraffic, _: &mut Self::Context) -> Self::Result {
+        let interval = msg.interval.map(|interval| std::cmp::max(interval, MIN_COVER_INTERVAL));
+        match self.rooms.get_mut(&msg.room_id) {
+            Some(room) if room.may_configure(&msg.id) => {
+                room.cover_interval = interval;
+            },
+            _ => {
+                self.send_error(&msg.id, ErrorCode::NOT_PERMITTED, "Only admins can change the cover traffic of this room.");
+                return;
+            },
+        }
+        let announcement = match interval {
+            None => format!("User {} has turned off cover traffic", msg.id),
+            Some(interval) => format!("User {} has turned on cover traffic every {} seconds on average", msg.id, interval),
+        };
+        self.broadcast_message(msg.room_id, "Server".to_string(), announcement, get_unix_time());
     }
 }
 
@@ -223,32 +2167,81 @@ impl Handler<Disconnect> for ChatServer {
 
     fn handle(&mut self, msg: Disconnect, _ctx: &mut Self::Context) -> Self::Result {
         //Pull client out the current room
-        match self.clients.get(&msg.id) {
-            None => {/*Do nothing*/},
+        let departure = match self.clients.get_mut(&msg.id) {
+            None => None,
             Some(client) => {
-                match self.rooms.get_mut(&client.room_id) {
-                    None => {
-                        println!("Impossible situation: client registered under nonexistent room.");
-                    },
-                    Some(peers) => {
-                        //Deregister the client from the current room ... broadcast disconnect to room
-                        peers.remove(&client.client_addr);
-                        let peer_count = peers.len();
+                //The client no longer belongs to any room ... its profile was only meant for the room it left
+                let previous_state = std::mem::replace(&mut client.state, ClientState::CONNECTED);
+                client.profile = None;
+                Some((client.room_id.clone(), client.client_addr.clone(), previous_state))
+            },
+        };
+
+        if let Some((room_id, client_addr, previous_state)) = departure {
+            match self.rooms.get_mut(&room_id) {
+                None => {
+                    println!("Impossible situation: client registered under nonexistent room.");
+                },
+                Some(room) => {
+                    //Deregister the client from the current room ... broadcast disconnect to room
+                    room.members.remove(&client_addr);
+                    let peer_count = room.members.len();
+                    if peer_count == 0 {
+                        room.release();
+                    }
+                    match previous_state {
                         //Only broadcast the disconnect message if the disconnecting client was validated
-                        if let ClientState::VALIDATED(_) = client.state {
-                            self.broadcast_message(client.room_id.clone(),
+                        ClientState::VALIDATED(_) => {
+                            //Votes to remove a member that has left no longer matter
+                            room.removal_votes.remove(&msg.id);
+                            for peer in room.members.iter() {
+                                peer.do_send(ServerPacket::Left {
+                                    id: msg.id.clone(),
+                                });
+                            }
+                            self.broadcast_message(room_id.clone(),
                                                    "Server".to_string(),
                                                    format!("{} disconnected ... Number of connected users: {}", msg.id, peer_count),
                                                    get_unix_time());
-                        }
-                    },
-                }
-            },
+                            //The client still knows the old keys ... members should rotate
+                            self.advance_epoch(&room_id);
+                        },
+                        //Peers were asked to validate this client ... let them know the request is gone
+                        ClientState::AWAITING_VALIDATION => {
+                            for peer in room.members.iter() {
+                                peer.do_send(ServerPacket::ValidationWithdrawn {
+                                    room_id: room_id.clone(),
+                                    id: msg.id.clone(),
+                                });
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+            }
         }
         //Completely deregister the client
         if msg.full_disconnect {
             self.clients.remove(&msg.id);
-            println!("Number of clients: {}", self.clients.len());
+            self.log_client_count();
+        }
+    }
+}
+
+impl Handler<Leave> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, ctx: &mut Self::Context) -> Self::Result {
+        self.handle(Disconnect {
+            id: msg.id.clone(),
+            full_disconnect: false,
+        }, ctx);
+
+        match self.clients.get(&msg.id) {
+            None => {},
+            Some(client) => {
+                client.client_addr.do_send(ClientState::CONNECTED);
+            },
         }
     }
-}
\ No newline at end of file
+}
@@ -4,9 +4,12 @@ use actix_web_actors::ws;
 use serde::{Serialize, Deserialize};
 
 use crate::chatserver;
-use crate::chatserver::ChatServer;
+use crate::envelope;
+use crate::chatserver::{ChatServer, ScheduledMessage, Member};
+use crate::history::StoredMessage;
 
 use std::time::{Instant, Duration};
+use std::collections::HashMap;
 
 //Module `chatsession.rs` holds the Actor given to each connecting WebSocket client
 //and this module acts on behalf of the WebSocket client when interacting with the
@@ -16,6 +19,8 @@ use std::time::{Instant, Duration};
 //Probe the client to see if it is still connected ... Give client 5 chances to send a heartbeat
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(6);
+//Ephemeral signals are cheap to drop ... only relay one signal of each kind per client in this window
+const SIGNAL_INTERVAL: Duration = Duration::from_secs(2);
 
 //Track the client's state with a state machine
 //This prevents clients from sending packets in an incorrect order
@@ -42,12 +47,254 @@ pub struct ValidationRequest {
 //This is a message sent by the server Actor to signify that a peer has
 //sent a message in the room. This message should be dispatched to every
 //client in the room.
-#[derive(Message, Serialize)]
+#[derive(Message, Serialize, Clone)]
 pub struct Text {
+    //Id given to the message by the server ... receipts refer back to this id
+    pub message_id: u64,
     pub id: String,
     pub message: String,
     //Time is given in terms of seconds since UNIX epoch
-    pub timestamp: u64
+    pub timestamp: u64,
+    //Id of the message this message replies to
+    pub parent_id: Option<u64>,
+    //Time (seconds since UNIX epoch) at which the message disappears
+    pub expires_at: Option<u64>,
+    //Membership epoch the sender encrypted the message for
+    pub epoch: Option<u64>,
+}
+
+//A text whose sender is sealed inside the ciphertext ... it is relayed without a sender id
+#[derive(Message, Serialize, Clone)]
+pub struct SealedText {
+    //Id given to the message by the server
+    pub message_id: u64,
+    pub message: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub timestamp: u64,
+    //Id of the message this message replies to
+    pub parent_id: Option<u64>,
+    //Time (seconds since UNIX epoch) at which the message disappears
+    pub expires_at: Option<u64>,
+    //Membership epoch the sender encrypted the message for
+    pub epoch: Option<u64>,
+}
+
+//Ephemeral signals are relayed to the room as is and never stored or sequenced
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub enum Signal {
+    TYPING,
+    STOPPED_TYPING,
+    FOCUSED,
+    UNFOCUSED,
+}
+
+impl Signal {
+    //The signal whose state this signal clears, if any
+    fn clears(&self) -> Option<Signal> {
+        match self {
+            Signal::STOPPED_TYPING => Some(Signal::TYPING),
+            Signal::UNFOCUSED => Some(Signal::FOCUSED),
+            Signal::TYPING | Signal::FOCUSED => None,
+        }
+    }
+}
+
+//Reasons the server gives for refusing a client's request
+#[derive(Serialize, Clone)]
+pub enum ErrorCode {
+    //The referenced message does not exist in the room
+    UNKNOWN_MESSAGE,
+    //The client lacks the rights for the request
+    NOT_PERMITTED,
+    //The client has too many pending requests of this kind
+    LIMIT_REACHED,
+    //The request carries more data than the server accepts
+    TOO_LARGE,
+    //The request was based on an outdated version of the data it changes
+    VERSION_CONFLICT,
+    //Only admins can send messages in the room
+    READ_ONLY,
+    //The room is locked and refuses newcomers
+    ROOM_LOCKED,
+    //The request carries a value the server does not accept
+    INVALID,
+    //The request was made for a membership epoch that has since ended
+    STALE_EPOCH,
+    //The text is not a well-formed encrypted envelope and the room is in strict mode
+    MALFORMED_ENVELOPE,
+    //The ciphertext of the text does not match any of the room's padding buckets
+    BAD_PADDING,
+}
+
+//Rules of a room that sessions enforce on packets sent by their client
+//Sent by the server when a client is validated into a room and whenever the rules change
+#[derive(Message, Serialize, Clone, Default)]
+pub struct RoomPolicy {
+    //Only relay texts that are well-formed encrypted envelopes
+    pub strict_envelopes: bool,
+    //Ciphertext lengths (in bytes) texts must be padded to ... empty if any length goes
+    pub padding_buckets: Vec<usize>,
+}
+
+//A dummy text sent by the server as cover traffic ... forwarded to the client like any other text
+//but never acknowledged, so the server and peers can't tell the client read it
+#[derive(Message)]
+pub struct Cover(pub Text);
+
+//Enumerates the packets that only the server sends to the client
+//Like `ClientPacket`, serde-json tags each variant so the WebSocket client can tell them apart
+#[derive(Message, Serialize, Clone)]
+pub enum ServerPacket {
+    //The server has refused a request made by the client
+    Error {
+        //Echo of the key the client attached to a refused text, if any
+        idempotency_key: Option<String>,
+        code: ErrorCode,
+        detail: String,
+    },
+    //Validated members of the room ... sent to a client once it has been validated into the room
+    Roster {
+        members: Vec<Member>,
+    },
+    //A peer has been validated into the room
+    Joined {
+        member: Member,
+    },
+    //A validated peer has left the room
+    Left {
+        id: String,
+    },
+    //A peer has updated its profile
+    Profile {
+        member: Member,
+    },
+    //Someone has joined or left the room ... members should rotate their keys for the new epoch
+    EpochChanged {
+        epoch: u64,
+        //Session ids of everyone in the room for the new epoch
+        members: Vec<String>,
+    },
+    //A peer has sent key material for the current epoch
+    Rekey {
+        id: String,
+        epoch: u64,
+        payload: String,
+    },
+    //Current epoch of the room's group and the session id of the client whose commit started it
+    //Sent to newcomers and along with errors for handshakes or sender keys for another group epoch
+    GroupEpoch {
+        epoch: u64,
+        committer: Option<String>,
+    },
+    //A peer has proposed a change to the room's group for the current group epoch
+    Proposal {
+        id: String,
+        epoch: u64,
+        payload: String,
+    },
+    //A peer's commit has been accepted ... the group is now at the given epoch
+    Commit {
+        id: String,
+        epoch: u64,
+        payload: String,
+    },
+    //The client has been welcomed into the room's group at the given epoch
+    Welcome {
+        id: String,
+        epoch: u64,
+        payload: String,
+    },
+    //A peer's sender key for the given group epoch ... encrypted for this client only
+    SenderKey {
+        id: String,
+        epoch: u64,
+        bundle: String,
+    },
+    //A member has been proposed for removal or has gained a vote towards it
+    RemovalVote {
+        //Session id of the member that may be removed
+        id: String,
+        votes: usize,
+        required: usize,
+    },
+    //A member has been voted out of the room
+    Removed {
+        id: String,
+        votes: usize,
+    },
+    //Rules of the room the client is in
+    Policy(RoomPolicy),
+    //Encrypted metadata of the room ... sent to newcomers and to every member when it changes
+    Metadata {
+        version: u64,
+        metadata: String,
+    },
+    //Messages stored by the room ... sent to a client once it has been validated into the room
+    History {
+        messages: Vec<StoredMessage>,
+    },
+    //Stored messages of a thread ... sent in response to a thread query
+    Thread {
+        //Id of the message that started the thread
+        message_id: u64,
+        messages: Vec<StoredMessage>,
+    },
+    //A message in the room has been replaced by its sender or an admin
+    Edited {
+        message_id: u64,
+        //Session id of the client that made the edit
+        id: String,
+        message: String,
+        timestamp: u64,
+    },
+    //A message in the room has been retracted by its sender or an admin
+    Deleted {
+        message_id: u64,
+        id: String,
+    },
+    //A peer that was awaiting validation has left before being accepted
+    ValidationWithdrawn {
+        room_id: String,
+        id: String,
+    },
+    //The server has relayed a text message sent by the client
+    Ack {
+        //Echo of the key the client attached to the message, if any
+        idempotency_key: Option<String>,
+        message_id: u64,
+    },
+    //Disappearing messages that have been purged by the server
+    Expired {
+        message_ids: Vec<u64>,
+    },
+    //A peer has reacted to a message or withdrawn its reaction
+    Reaction {
+        message_id: u64,
+        id: String,
+        //Encrypted reaction ... `None` if the peer withdrew its reaction
+        reaction: Option<String>,
+    },
+    //Texts the client has scheduled that are still pending ... sent when a text is scheduled or when asked for
+    Scheduled {
+        messages: Vec<ScheduledMessage>,
+    },
+    //A scheduled text has been cancelled and will not be delivered
+    ScheduleCancelled {
+        schedule_id: u64,
+    },
+    //Receipts collected so far for a message the client has sent
+    Receipt {
+        message_id: u64,
+        //Ids of peers the message has been delivered to
+        delivered: Vec<String>,
+        //Ids of peers that have read the message
+        read: Vec<String>,
+    },
+    //A peer in the room has sent an ephemeral signal
+    Signal {
+        id: String,
+        signal: Signal,
+    },
 }
 
 //Enumerates the valid packets that the client may send
@@ -59,7 +306,16 @@ pub enum ClientPacket {
         room_id: String,
         //The validation field should be the HMAC of the client's id under some secret key unknown to server but
         //known to peers ... peers in the room will validate the incoming peer
-        validation: String
+        validation: String,
+        //Ask to become the owner of the room if it does not exist yet
+        #[serde(default)]
+        claim_ownership: bool,
+        //Encrypted profile (display name, avatar hash) to show to the room
+        #[serde(default)]
+        profile: Option<String>,
+        //One-time invite code handed out by a member of the room
+        #[serde(default)]
+        invite_code: Option<String>,
     },
     //This packet is sent both by the client and the session Actor
     //The session Actor sends this packet as opposed to the struct above as
@@ -73,7 +329,183 @@ pub enum ClientPacket {
     //This packet is sent by the WebSocket client to dispatch a message across the room
     Text {
         message: String,
+        //Id of the message being replied to ... replies form a thread under the message
+        #[serde(default)]
+        parent_id: Option<u64>,
+        //Seconds the message should live for ... the room's time to live applies if it is shorter
+        #[serde(default)]
+        ttl: Option<u64>,
+        //Membership epoch the message was encrypted for ... the server refuses messages for an old epoch
+        #[serde(default)]
+        epoch: Option<u64>,
+        //Optional key unique to the message ... resending with the same key will not post the message twice
+        #[serde(default)]
+        idempotency_key: Option<String>,
+    },
+    //This packet is sent by the WebSocket client to send a text whose sender identity is sealed inside
+    //the ciphertext ... the server relays it to the room without a sender id
+    SealedText {
+        message: String,
+        #[serde(default)]
+        parent_id: Option<u64>,
+        #[serde(default)]
+        ttl: Option<u64>,
+        #[serde(default)]
+        epoch: Option<u64>,
+    },
+    //This packet is sent by the WebSocket client to have a text delivered to the room at a later time
+    //The server holds the text and delivers it even if the client has disconnected by then
+    Schedule {
+        message: String,
+        //Time is given in terms of seconds since UNIX epoch
+        deliver_at: u64,
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    //This packet is sent by the WebSocket client to list its pending scheduled texts
+    ListScheduled,
+    //This packet is sent by the WebSocket client to cancel one of its pending scheduled texts
+    CancelScheduled {
+        schedule_id: u64,
+    },
+    //This packet is sent by the WebSocket client to fetch the messages of a thread
+    Thread {
+        message_id: u64,
+    },
+    //This packet is sent by the WebSocket client to replace the contents of a message
+    //Only the sender of the message or an admin of the room may edit it
+    Edit {
+        message_id: u64,
+        message: String,
+    },
+    //This packet is sent by the WebSocket client to retract a message
+    //Only the sender of the message or an admin of the room may delete it
+    Delete {
+        message_id: u64,
+    },
+    //This packet is sent by the WebSocket client to react to a message
+    //The reaction should be encrypted like a text message ... leaving it out withdraws the client's reaction
+    React {
+        message_id: u64,
+        #[serde(default)]
+        reaction: Option<String>,
+    },
+    //This packet is sent by the owner of a room to grant or revoke a member's admin rights
+    SetAdmin {
+        id: String,
+        admin: bool,
+    },
+    //This packet is sent by the WebSocket client once the user has read a message
+    Read {
+        message_id: u64,
+    },
+    //This packet is sent by the WebSocket client to turn delivery and read receipts on or off for the room
+    SetReceipts {
+        enabled: bool,
+    },
+    //This packet is sent by the WebSocket client to make messages in the room disappear after some seconds
+    //Leaving out the time to live turns disappearing messages off
+    SetRoomTtl {
+        #[serde(default)]
+        ttl: Option<u64>,
+    },
+    //This packet is sent by the WebSocket client to replace the encrypted metadata of the room
+    //The version must be that of the latest metadata the client has received (0 if there is none)
+    SetMetadata {
+        version: u64,
+        metadata: String,
+    },
+    //This packet is sent by the WebSocket client to update the encrypted profile it shows to the room
+    SetProfile {
+        #[serde(default)]
+        profile: Option<String>,
+    },
+    //This packet is sent by the owner of a room to make the room read-only for everyone but its admins
+    SetReadOnly {
+        read_only: bool,
+    },
+    //This packet is sent by the owner or an admin of a room to lock or unlock it
+    //A locked room refuses every registration without asking its members
+    SetLocked {
+        locked: bool,
+    },
+    //This packet is sent by a validated client to admit a specific peer id ahead of time
+    PreAuthorize {
+        id: String,
+    },
+    //This packet is sent by a validated client to register a one-time invite code for the room
+    //The code should be random and only shared with the invitee
+    CreateInvite {
+        invite_code: String,
+    },
+    //This packet is sent by a validated client to vote for removing a member from the room
+    //The first vote proposes the removal
+    VoteRemove {
+        id: String,
+    },
+    //This packet is sent by the WebSocket client to set the percentage of members needed to remove someone
+    SetRemovalQuorum {
+        quorum: u8,
+    },
+    //This packet is sent by a validated client to hand key material to the room for the current epoch
+    Rekey {
+        epoch: u64,
+        payload: String,
+    },
+    //These packets are sent by validated clients to run a group key agreement (MLS) in the room
+    //The server orders them: one commit is accepted per group epoch and welcomes only reach the named joiners
+    Proposal {
+        epoch: u64,
+        payload: String,
+    },
+    Commit {
+        epoch: u64,
+        payload: String,
+    },
+    Welcome {
+        epoch: u64,
+        recipients: Vec<String>,
+        payload: String,
+    },
+    //This packet is sent by a validated client to distribute its sender key for the current group epoch
+    //Each bundle is encrypted for one recipient ... the server only delivers a recipient its own bundle
+    //and holds bundles for recipients that have yet to join until the epoch ends
+    SenderKeys {
+        epoch: u64,
+        //Maps recipient ids to their encrypted bundle
+        bundles: HashMap<String, String>,
+    },
+    //This packet is sent by the WebSocket client to turn strict mode on or off for the room
+    //In strict mode every text must be a well-formed encrypted envelope
+    SetStrictMode {
+        strict: bool,
+    },
+    //This packet is sent by the WebSocket client to require texts in the room to be padded
+    //Buckets are ciphertext lengths in bytes and must be multiples of the AES block size ... an empty list turns padding off
+    SetPadding {
+        buckets: Vec<usize>,
+    },
+    //This packet is sent by the WebSocket client to have the server send dummy frames to the room's members
+    //at random times averaging the given number of seconds ... leaving out the interval turns it off
+    SetCoverTraffic {
+        #[serde(default)]
+        interval: Option<u64>,
+    },
+    //This packet is sent by the WebSocket client as cover traffic ... the server drops it without relaying it
+    Cover {
+        payload: String,
+    },
+    //This packet is sent by the WebSocket client to let the room know about transient activity
+    //Signals are rate limited and the server drops them before anything else when busy
+    Signal {
+        signal: Signal,
+    },
+    //This packet is sent by the client to leave the room it has been validated into
+    Leave {
+        room_id: String,
     },
+    //This packet is sent by the client to withdraw a pending request to join a room
+    CancelJoin,
     HEARTBEAT(String),
 }
 
@@ -83,6 +515,69 @@ pub struct ChatSession {
     pub server_addr: Addr<ChatServer>,
     pub state: ClientState,
     pub last_heartbeat: Instant,
+    //Maps each kind of signal to when the client last had one relayed
+    pub last_signals: HashMap<Signal, Instant>,
+    //Rules of the room the client is validated into
+    pub policy: RoomPolicy,
+    //Keep relayed texts out of the logs since they carry session ids
+    pub private_logs: bool,
+}
+
+impl ChatSession {
+    //Rooms in strict mode only accept well-formed encrypted envelopes ... anything else is refused
+    //so that a buggy client can't leak plaintext into the room
+    //Rooms with padding buckets also need the ciphertext to be padded to one of the bucket sizes
+    fn check_envelope(&self, message: &str, idempotency_key: &Option<String>, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if !self.policy.strict_envelopes && self.policy.padding_buckets.is_empty() {
+            return true;
+        }
+        let result = envelope::parse(message)
+            .map_err(|detail| (ErrorCode::MALFORMED_ENVELOPE, detail))
+            .and_then(|parsed| {
+                let padded = self.policy.padding_buckets.is_empty()
+                    || self.policy.padding_buckets.contains(&parsed.ciphertext.len());
+                if padded {
+                    Ok(())
+                } else {
+                    Err((ErrorCode::BAD_PADDING, "The ciphertext is not padded to one of the room's bucket sizes.".to_string()))
+                }
+            });
+        match result {
+            Ok(_) => true,
+            Err((code, detail)) => {
+                ctx.text(serde_json::to_string(&ServerPacket::Error {
+                    idempotency_key: idempotency_key.clone(),
+                    code,
+                    detail,
+                }).unwrap());
+                false
+            },
+        }
+    }
+
+    //Sends a frame padded to the room's buckets so an observer can't tell texts and events apart by size
+    //Only replies to the client's own malformed packets are sent unpadded ... they reveal nothing the
+    //client's packet didn't already
+    fn send_frame(&self, mut frame: String, ctx: &mut ws::WebsocketContext<Self>) {
+        envelope::pad_frame(&mut frame, &self.policy.padding_buckets);
+        ctx.text(frame);
+    }
+
+    //Forwards a group key agreement message to the server on behalf of a validated client
+    fn send_handshake(&self, handshake: chatserver::Handshake, ctx: &mut ws::WebsocketContext<Self>) {
+        match &self.state {
+            ClientState::VALIDATED(room_id) => {
+                self.server_addr.do_send(chatserver::HandshakeMessage {
+                    id: self.id.clone(),
+                    room_id: room_id.clone(),
+                    handshake,
+                });
+            },
+            _ => {
+                ctx.text("You must be validated into a room to take part in its key agreement.")
+            }
+        }
+    }
 }
 
 //Make the ChatSession an Actor object
@@ -97,7 +592,7 @@ impl Actor for ChatSession {
         //Register a heart beat monitor
         ctx.run_interval(HEARTBEAT_INTERVAL, |actor, context| {
             //Ping the client with a heartbeat message
-            context.text(serde_json::to_string(&ClientPacket::HEARTBEAT(actor.id.clone())).unwrap());
+            actor.send_frame(serde_json::to_string(&ClientPacket::HEARTBEAT(actor.id.clone())).unwrap(), context);
             if Instant::now().duration_since(actor.last_heartbeat) > CLIENT_TIMEOUT {
                 //No heartbeat ... disconnect the client
                 context.stop();
@@ -118,8 +613,37 @@ impl Handler<Text> for ChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: Text, ctx: &mut Self::Context) {
-        println!("{}", serde_json::to_string(&msg).unwrap());
-        ctx.text(serde_json::to_string(&msg).unwrap());
+        if !self.private_logs {
+            println!("{}", serde_json::to_string(&msg).unwrap());
+        }
+        self.send_frame(serde_json::to_string(&msg).unwrap(), ctx);
+        //Acknowledge delivery of messages sent by peers
+        if msg.id != self.id {
+            self.server_addr.do_send(chatserver::Receipt {
+                id: self.id.clone(),
+                message_id: msg.message_id,
+                read: false,
+            });
+        }
+    }
+}
+
+//Server is sending a sealed text ... it is neither logged nor acknowledged with a receipt since
+//either would tie the text back to this session
+impl Handler<SealedText> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: SealedText, ctx: &mut Self::Context) {
+        self.send_frame(serde_json::to_string(&msg).unwrap(), ctx);
+    }
+}
+
+//Server is sending cover traffic
+impl Handler<Cover> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Cover, ctx: &mut Self::Context) {
+        self.send_frame(serde_json::to_string(&msg.0).unwrap(), ctx);
     }
 }
 
@@ -129,7 +653,33 @@ impl Handler<ClientState> for ChatSession {
 
     fn handle(&mut self, msg: ClientState, ctx: &mut Self::Context) -> Self::Result {
         self.state = msg;
-        ctx.text(serde_json::to_string(&self.state).unwrap());
+        //Rules only apply to the room the client was in
+        match self.state {
+            ClientState::VALIDATED(_) => {},
+            _ => {
+                self.policy = RoomPolicy::default();
+            },
+        }
+        self.send_frame(serde_json::to_string(&self.state).unwrap(), ctx);
+    }
+}
+
+//Server has set the rules of the client's room
+impl Handler<RoomPolicy> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoomPolicy, ctx: &mut Self::Context) -> Self::Result {
+        self.policy = msg;
+        self.send_frame(serde_json::to_string(&ServerPacket::Policy(self.policy.clone())).unwrap(), ctx);
+    }
+}
+
+//Server has an event for the client
+impl Handler<ServerPacket> for ChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerPacket, ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(serde_json::to_string(&msg).unwrap(), ctx);
     }
 }
 
@@ -142,12 +692,12 @@ impl Handler<ValidationRequest> for ChatSession {
         //Verify that the current client is validated ... unvalidated people should not be able
         //to validate others
         if let ClientState::VALIDATED(_) = self.state {
-            ctx.text(serde_json::to_string(&ClientPacket::ValidationRequest {
+            self.send_frame(serde_json::to_string(&ClientPacket::ValidationRequest {
                 room_id: msg.room_id,
                 id: msg.id,
                 validation: msg.validation,
                 accept: false
-            }).unwrap());
+            }).unwrap(), ctx);
         }
     }
 }
@@ -165,12 +715,15 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for ChatSession {
                     Ok(packet) => {
                         match packet {
                             //Contact server with registration request
-                            ClientPacket::Register {room_id, validation} => {
+                            ClientPacket::Register {room_id, validation, claim_ownership, profile, invite_code} => {
                                 self.server_addr.do_send(chatserver::Register {
                                     id: self.id.clone(),
                                     room_id,
                                     validation,
-                                    addr: ctx.address()
+                                    addr: ctx.address(),
+                                    claim_ownership,
+                                    profile,
+                                    invite_code,
                                 })
                             },
                             //Client has sent a validation request ... redirect to server Actor
@@ -194,18 +747,442 @@ impl StreamHandler<ws::Message, ws::ProtocolError> for ChatSession {
                                 }
 
                             },
-                            ClientPacket::Text { message } => {
+                            ClientPacket::Text { message, parent_id, ttl, epoch, idempotency_key } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        if self.check_envelope(&message, &idempotency_key, ctx) {
+                                            self.server_addr.do_send(chatserver::Message {
+                                                id: self.id.clone(),
+                                                room_id: room_id.clone(),
+                                                message,
+                                                timestamp: chatserver::get_unix_time(),
+                                                parent_id,
+                                                ttl,
+                                                epoch,
+                                                idempotency_key,
+                                            });
+                                        }
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to sent a text message.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SealedText { message, parent_id, ttl, epoch } => {
                                 match &self.state {
                                     ClientState::VALIDATED(room_id) => {
-                                        self.server_addr.do_send(chatserver::Message {
+                                        if self.check_envelope(&message, &None, ctx) {
+                                            self.server_addr.do_send(chatserver::SealedMessage {
+                                                addr: ctx.address(),
+                                                room_id: room_id.clone(),
+                                                message,
+                                                timestamp: chatserver::get_unix_time(),
+                                                parent_id,
+                                                ttl,
+                                                epoch,
+                                            });
+                                        }
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to send a sealed text.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Schedule { message, deliver_at, ttl } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        if self.check_envelope(&message, &None, ctx) {
+                                            self.server_addr.do_send(chatserver::Schedule {
+                                                id: self.id.clone(),
+                                                room_id: room_id.clone(),
+                                                message,
+                                                deliver_at,
+                                                ttl,
+                                            });
+                                        }
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to schedule a text message.")
+                                    }
+                                }
+                            },
+                            ClientPacket::ListScheduled => {
+                                self.server_addr.do_send(chatserver::ListScheduled {
+                                    id: self.id.clone(),
+                                });
+                            },
+                            ClientPacket::CancelScheduled { schedule_id } => {
+                                self.server_addr.do_send(chatserver::CancelScheduled {
+                                    id: self.id.clone(),
+                                    schedule_id,
+                                });
+                            },
+                            ClientPacket::Thread { message_id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::ThreadQuery {
                                             id: self.id.clone(),
                                             room_id: room_id.clone(),
-                                            message,
-                                            timestamp: chatserver::get_unix_time(),
+                                            message_id,
                                         });
                                     },
                                     _ => {
-                                        ctx.text("You must be validated into a room to sent a text message.")
+                                        ctx.text("You must be validated into a room to fetch a thread.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Edit { message_id, message } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        if self.check_envelope(&message, &None, ctx) {
+                                            self.server_addr.do_send(chatserver::Edit {
+                                                id: self.id.clone(),
+                                                room_id: room_id.clone(),
+                                                message_id,
+                                                message,
+                                            });
+                                        }
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to edit a message.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Delete { message_id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::Delete {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            message_id,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to delete a message.")
+                                    }
+                                }
+                            },
+                            ClientPacket::React { message_id, reaction } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::React {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            message_id,
+                                            reaction,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to react to a message.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetAdmin { id, admin } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetAdmin {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            target_id: id,
+                                            admin,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its admins.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Read { message_id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(_) => {
+                                        self.server_addr.do_send(chatserver::Receipt {
+                                            id: self.id.clone(),
+                                            message_id,
+                                            read: true,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to send a read receipt.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetReceipts { enabled } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetReceipts {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            enabled,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its receipt setting.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetRoomTtl { ttl } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetRoomTtl {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            ttl,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its disappearing messages.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetMetadata { version, metadata } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetMetadata {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            version,
+                                            metadata,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its metadata.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetProfile { profile } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetProfile {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            profile,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to update your profile.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetReadOnly { read_only } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetReadOnly {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            read_only,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its announcement mode.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetLocked { locked } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetLocked {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            locked,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to lock it.")
+                                    }
+                                }
+                            },
+                            ClientPacket::PreAuthorize { id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::PreAuthorize {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            admission: chatserver::Admission::Identity(id),
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to pre-approve a peer.")
+                                    }
+                                }
+                            },
+                            ClientPacket::CreateInvite { invite_code } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::PreAuthorize {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            admission: chatserver::Admission::InviteCode(invite_code),
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to create an invite.")
+                                    }
+                                }
+                            },
+                            ClientPacket::VoteRemove { id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::VoteRemove {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            target_id: id,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to vote on removing a member.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetRemovalQuorum { quorum } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetRemovalQuorum {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            quorum,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its removal quorum.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Rekey { epoch, payload } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::Rekey {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            epoch,
+                                            payload,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to send key material.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Proposal { epoch, payload } => {
+                                self.send_handshake(chatserver::Handshake::Proposal { epoch, payload }, ctx);
+                            },
+                            ClientPacket::Commit { epoch, payload } => {
+                                self.send_handshake(chatserver::Handshake::Commit { epoch, payload }, ctx);
+                            },
+                            ClientPacket::Welcome { epoch, recipients, payload } => {
+                                self.send_handshake(chatserver::Handshake::Welcome { epoch, recipients, payload }, ctx);
+                            },
+                            ClientPacket::SenderKeys { epoch, bundles } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SenderKeys {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            epoch,
+                                            bundles,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to distribute sender keys.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetStrictMode { strict } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetStrictMode {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            strict,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its strict mode.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetPadding { buckets } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetPadding {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            buckets,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its padding.")
+                                    }
+                                }
+                            },
+                            ClientPacket::SetCoverTraffic { interval } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        self.server_addr.do_send(chatserver::SetCoverTraffic {
+                                            id: self.id.clone(),
+                                            room_id: room_id.clone(),
+                                            interval,
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to change its cover traffic.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Cover { .. } => {/*Do nothing ... cover traffic is never relayed*/},
+                            ClientPacket::Signal { signal } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(room_id) => {
+                                        let now = Instant::now();
+                                        let relay = match signal.clears() {
+                                            //A state-clearing signal is relayed once for every relayed signal it clears
+                                            //... it is never held back by the interval so peers aren't left thinking the
+                                            //client is still typing
+                                            Some(cleared) => match (self.last_signals.get(&cleared), self.last_signals.get(&signal)) {
+                                                (None, _) => false,
+                                                (Some(_), None) => true,
+                                                (Some(set_at), Some(cleared_at)) => set_at > cleared_at,
+                                            },
+                                            //Silently drop signals sent too quickly
+                                            None => match self.last_signals.get(&signal) {
+                                                None => true,
+                                                Some(last_signal) => now.duration_since(*last_signal) >= SIGNAL_INTERVAL,
+                                            },
+                                        };
+                                        if relay {
+                                            self.last_signals.insert(signal.clone(), now);
+                                            self.server_addr.do_send(chatserver::Signal {
+                                                id: self.id.clone(),
+                                                room_id: room_id.clone(),
+                                                signal,
+                                            });
+                                        }
+                                    },
+                                    _ => {
+                                        ctx.text("You must be validated into a room to send a signal.")
+                                    }
+                                }
+                            },
+                            ClientPacket::Leave { room_id } => {
+                                match &self.state {
+                                    ClientState::VALIDATED(validated_room_id) if validated_room_id == &room_id => {
+                                        self.server_addr.do_send(chatserver::Leave {
+                                            id: self.id.clone(),
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("You can only leave a room you have been validated into.");
+                                    }
+                                }
+                            },
+                            ClientPacket::CancelJoin => {
+                                match &self.state {
+                                    ClientState::AWAITING_VALIDATION => {
+                                        self.server_addr.do_send(chatserver::Leave {
+                                            id: self.id.clone(),
+                                        });
+                                    },
+                                    _ => {
+                                        ctx.text("There is no pending request to join a room.");
                                     }
                                 }
                             },
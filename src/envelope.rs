@@ -0,0 +1,92 @@
+//Module `envelope.rs` checks the structure of encrypted text messages without decrypting them
+//The bundled client sends `iv|ciphertext|hmac` where each field is base64 encoded. Rooms in strict
+//mode only relay messages in this shape so that a misconfigured client can't leak plaintext, and rooms
+//with padding buckets only relay ciphertexts of the bucket sizes so message lengths leak less
+
+//Envelope versions the server knows how to check ... unversioned envelopes are version 1
+const SUPPORTED_VERSIONS: [&str; 1] = ["1"];
+//AES-CBC uses a 16 byte IV ... the bundled client generates 32 random bytes of which AES uses the first 16
+const IV_LENGTHS: [usize; 2] = [16, 32];
+pub const BLOCK_SIZE: usize = 16;
+//HMAC-SHA256
+const HMAC_LENGTH: usize = 32;
+//Room left in padded frames for the JSON around an envelope (message id, sender id, timestamps and so on)
+pub const FRAME_OVERHEAD: usize = 256;
+
+//A well-formed envelope
+pub struct Envelope {
+    pub iv: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub hmac: Vec<u8>,
+}
+
+fn decode_field(field: &str, name: &str) -> Result<Vec<u8>, String> {
+    base64::decode(field).map_err(|_| format!("The {} of the envelope is not valid base64.", name))
+}
+
+//Length of an unversioned envelope as sent by the bundled client for a ciphertext of the given length
+//`None` if the length doesn't fit in a usize
+pub fn envelope_length(ciphertext_length: usize) -> Option<usize> {
+    let base64_length = |length: usize| length.checked_add(2).and_then(|length| (length / 3).checked_mul(4));
+    base64_length(IV_LENGTHS[1])?
+        .checked_add(1)?
+        .checked_add(base64_length(ciphertext_length)?)?
+        .checked_add(1)?
+        .checked_add(base64_length(HMAC_LENGTH)?)
+}
+
+//Pads with spaces up to the smallest target that fits ... left as is if longer than every target
+fn pad_to(message: &mut String, targets: impl Iterator<Item = usize>) {
+    let target = targets
+        .filter(|length| *length >= message.len())
+        .min();
+    if let Some(target) = target {
+        let padding = target - message.len();
+        message.push_str(&" ".repeat(padding));
+    }
+}
+
+//Pads a plaintext server message with spaces so it is as long as an envelope in one of the buckets
+//Messages longer than every bucket are left as they are
+pub fn pad_to_bucket(message: &mut String, buckets: &[usize]) {
+    pad_to(message, buckets.iter().filter_map(|bucket| envelope_length(*bucket)));
+}
+
+//Pads a JSON frame sent to a client with trailing whitespace so that texts and server events of a room
+//come in the same few sizes ... frames larger than every bucket (eg. history) are left as they are
+pub fn pad_frame(frame: &mut String, buckets: &[usize]) {
+    pad_to(frame, buckets.iter().filter_map(|bucket| envelope_length(*bucket)?.checked_add(FRAME_OVERHEAD)));
+}
+
+//Parses `[version|]iv|ciphertext|hmac` and checks every field
+//`usize::is_multiple_of` needs a newer compiler than the project supports
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+pub fn parse(message: &str) -> Result<Envelope, String> {
+    let fields: Vec<&str> = message.split('|').collect();
+    let (iv, ciphertext, hmac) = match fields.len() {
+        3 => (fields[0], fields[1], fields[2]),
+        4 => {
+            if !SUPPORTED_VERSIONS.contains(&fields[0]) {
+                return Err(format!("Envelope version {} is not supported.", fields[0]));
+            }
+            (fields[1], fields[2], fields[3])
+        },
+        _ => return Err("The message is not an encrypted envelope.".to_string()),
+    };
+
+    let envelope = Envelope {
+        iv: decode_field(iv, "IV")?,
+        ciphertext: decode_field(ciphertext, "ciphertext")?,
+        hmac: decode_field(hmac, "HMAC")?,
+    };
+    if !IV_LENGTHS.contains(&envelope.iv.len()) {
+        return Err("The IV of the envelope has the wrong length.".to_string());
+    }
+    if envelope.ciphertext.is_empty() || envelope.ciphertext.len() % BLOCK_SIZE != 0 {
+        return Err("The ciphertext of the envelope is not a whole number of blocks.".to_string());
+    }
+    if envelope.hmac.len() != HMAC_LENGTH {
+        return Err("The HMAC of the envelope has the wrong length.".to_string());
+    }
+    Ok(envelope)
+}
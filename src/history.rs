@@ -0,0 +1,102 @@
+use serde::Serialize;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+//Module `history.rs` keeps the most recent messages of a room so that newcomers can
+//catch up and senders can later edit or retract what they have sent. Messages are
+//stored exactly as they were relayed ... the server only ever holds ciphertext
+
+//Only the most recent messages of a room are kept
+const MAX_HISTORY: usize = 100;
+
+//A message as it is stored by the server and replayed to newcomers
+#[derive(Serialize, Clone)]
+pub struct StoredMessage {
+    pub message_id: u64,
+    //Session id of the sender ... `None` for sealed texts whose sender only the room knows
+    pub id: Option<String>,
+    pub message: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub timestamp: u64,
+    //Id of the message this message replies to
+    pub parent_id: Option<u64>,
+    //Time (seconds since UNIX epoch) at which the message is purged
+    pub expires_at: Option<u64>,
+    pub edited: bool,
+    //Maps the session id of each reacting member to its encrypted reaction
+    pub reactions: HashMap<String, String>,
+}
+
+pub struct History {
+    //Messages ordered from oldest to newest
+    messages: VecDeque<StoredMessage>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            messages: VecDeque::new(),
+        }
+    }
+
+    //Stores a message ... the oldest message is dropped when the history is full
+    pub fn push(&mut self, message: StoredMessage) {
+        if self.messages.len() >= MAX_HISTORY {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    pub fn get(&self, message_id: u64) -> Option<&StoredMessage> {
+        self.messages.iter().find(|message| message.message_id == message_id)
+    }
+
+    pub fn get_mut(&mut self, message_id: u64) -> Option<&mut StoredMessage> {
+        self.messages.iter_mut().find(|message| message.message_id == message_id)
+    }
+
+    //Purges a message from the history
+    pub fn remove(&mut self, message_id: u64) -> Option<StoredMessage> {
+        let position = self.messages.iter().position(|message| message.message_id == message_id)?;
+        self.messages.remove(position)
+    }
+
+    //Purges every message that has outlived its time to live and returns their ids
+    pub fn expire(&mut self, now: u64) -> Vec<u64> {
+        let mut expired = Vec::new();
+        self.messages.retain(|message| {
+            match message.expires_at {
+                Some(expires_at) if expires_at <= now => {
+                    expired.push(message.message_id);
+                    false
+                },
+                _ => true,
+            }
+        });
+        expired
+    }
+
+    //Messages of the thread started by a message ... the first message is the one that started the thread
+    //Replies to replies belong to the same thread
+    pub fn thread(&self, message_id: u64) -> Vec<StoredMessage> {
+        let mut thread_ids: HashSet<u64> = HashSet::new();
+        let mut thread = Vec::new();
+        //Parents are always stored before their replies
+        for message in self.messages.iter() {
+            let in_thread = message.message_id == message_id || match message.parent_id {
+                None => false,
+                Some(parent_id) => thread_ids.contains(&parent_id),
+            };
+            if in_thread {
+                thread_ids.insert(message.message_id);
+                thread.push(message.clone());
+            }
+        }
+        thread
+    }
+
+    //Copy of the stored messages from oldest to newest
+    pub fn replay(&self) -> Vec<StoredMessage> {
+        self.messages.iter().cloned().collect()
+    }
+}
@@ -9,6 +9,10 @@ extern crate base64;
 
 mod chatserver;
 mod chatsession;
+mod envelope;
+mod history;
+mod transparency;
+#[cfg(test)]
 mod test;
 
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
@@ -22,10 +26,13 @@ use rand::prelude::*;
 
 use serde::Deserialize;
 
-use crate::chatserver::ChatServer;
-use crate::chatsession::{ChatSession, ClientState};
+use crate::chatserver::{ChatServer, CoverTrafficConfig, PrivacyConfig};
+use crate::chatsession::{ChatSession, ClientState, RoomPolicy};
+use crate::transparency::TransparencyLog;
 use actix_web::web::Path;
 use std::time::Instant;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use openssl::ssl::{SslAcceptor, SslMethod, SslFiletype};
 
 //Converts contents of a file into a HttpResponse
@@ -61,7 +68,8 @@ fn get_asset(req: Path<AssetPath>) -> HttpResponse {
 }
 
 //Set up client with a session ... called every time a WebSocket client hits WebSocket endpoint
-fn chat(req: HttpRequest, stream: web::Payload, server: web::Data<Addr<ChatServer>>) -> Result<HttpResponse, Error> {
+//The remote address of the request is never read so it can't end up in the logs
+fn chat(req: HttpRequest, stream: web::Payload, server: web::Data<Addr<ChatServer>>, privacy: web::Data<PrivacyConfig>) -> Result<HttpResponse, Error> {
     //Set up the session
     let mut rng = rand::thread_rng();
     let id_bytes: [u8; 8] = rng.gen();
@@ -71,9 +79,14 @@ fn chat(req: HttpRequest, stream: web::Payload, server: web::Data<Addr<ChatServe
         server_addr: server.get_ref().clone(),
         state: ClientState::INIT,
         last_heartbeat: Instant::now(),
+        last_signals: HashMap::new(),
+        policy: RoomPolicy::default(),
+        private_logs: privacy.enabled,
     };
 
-    println!("connected user {}!", session.id);
+    if !privacy.enabled {
+        println!("connected user {}!", session.id);
+    }
     let resp = ws::start(session, &req, stream);
     resp
 }
@@ -83,32 +96,67 @@ struct ServerConfig {
     ip: String,
     private_key_path: String,
     certificate_path: String,
+    //File the key transparency log is persisted to
+    #[serde(default = "default_transparency_log_path")]
+    transparency_log_path: String,
+    //Server wide caps on the cover traffic rooms can ask for
+    #[serde(default)]
+    cover_traffic: CoverTrafficConfig,
+    //Only accept fixed length room ids derived from the room secret and keep room ids out of the logs
+    #[serde(default)]
+    blinded_room_ids: bool,
+    //Keep identifiers out of the logs and round timestamps on relayed messages
+    #[serde(default)]
+    privacy: PrivacyConfig,
+}
+
+fn default_transparency_log_path() -> String {
+    String::from("./transparency_log.jsonl")
 }
 
 fn main() {
     let sys = System::new("chatserver");
-    let chat_server = chatserver::ChatServer::new().start();
 
     //Configurations
     let config_file = std::fs::read("./config.json").unwrap();
     let server_config: ServerConfig = serde_json::from_str(&String::from_utf8(config_file).unwrap()).unwrap();
 
+    let chat_server = chatserver::ChatServer::new(server_config.cover_traffic.clone(),
+                                                  server_config.blinded_room_ids,
+                                                  server_config.privacy.clone()).start();
+
     //Place SSL certs in the project's source directory
     //Self-sign certificate: `openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=ENDPOINT_IP'`
 
+    //Tree heads of the key transparency log are signed with the server's private key
+    let transparency_log = web::Data::new(Mutex::new(
+        TransparencyLog::open(&server_config.transparency_log_path, &server_config.private_key_path).unwrap()
+    ));
+
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
     builder
         .set_private_key_file(server_config.private_key_path, SslFiletype::PEM)
         .unwrap();
     builder.set_certificate_chain_file(server_config.certificate_path).unwrap();
 
+    let privacy = server_config.privacy.clone();
     println!("Server is running at: https://{}", server_config.ip);
     HttpServer::new(move || {
         App::new()
             //The chat server address should be shared with every connecting client
             .data(chat_server.clone())
+            //Sessions need to know whether they may log relayed texts
+            .data(privacy.clone())
+            //Every worker appends to the same transparency log
+            .register_data(transparency_log.clone())
             .service(web::resource("/").to(index))
             .route("/chat", web::get().to(chat))
+            .route("/transparency/entries", web::post().to(transparency::publish))
+            .route("/transparency/entries/{index}", web::get().to(transparency::entry))
+            .route("/transparency/keys/{identity}", web::get().to(transparency::lookup))
+            .route("/transparency/head", web::get().to(transparency::tree_head))
+            .route("/transparency/inclusion/{index}/{tree_size}", web::get().to(transparency::inclusion_proof))
+            .route("/transparency/consistency/{first}/{second}", web::get().to(transparency::consistency_proof))
             .route("/{path}", web::get().to(get_asset))
     })
         .bind_ssl(server_config.ip, builder)
@@ -1,4 +1,381 @@
+use openssl::sha::sha256;
+
+use std::collections::HashMap;
+
+use crate::chatserver::{removal_votes_required, round_down, round_up};
+use crate::envelope;
+use crate::history::{History, StoredMessage};
+use crate::transparency::{Hash, MerkleTree, root_hash};
+
 #[test]
 fn foo_test() {
     assert!(true);
-}
\ No newline at end of file
+}
+
+//Merkle log of `transparency.rs` ... proofs are checked with the verification algorithms of RFC 9162
+//rather than by recomputing them the way the log does
+
+//Leaves and tree heads used by the RFC 6962 reference implementation
+const TEST_LEAVES: [&[u8]; 8] = [
+    b"",
+    b"\x00",
+    b"\x10",
+    b"\x20\x21",
+    b"\x30\x31",
+    b"\x40\x41\x42\x43",
+    b"\x50\x51\x52\x53\x54\x55\x56\x57",
+    b"\x60\x61\x62\x63\x64\x65\x66\x67\x68\x69\x6a\x6b\x6c\x6d\x6e\x6f",
+];
+const TEST_ROOTS: [&str; 8] = [
+    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+    "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+    "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+    "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+    "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+    "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+    "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+];
+
+fn test_leaf_hashes() -> Vec<Hash> {
+    TEST_LEAVES.iter().map(|leaf| {
+        let mut data = vec![0x00];
+        data.extend_from_slice(leaf);
+        sha256(&data)
+    }).collect()
+}
+
+fn test_tree(leaves: &[Hash]) -> MerkleTree {
+    let mut tree = MerkleTree::new();
+    for leaf in leaves {
+        tree.push(*leaf);
+    }
+    tree
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    let mut data = vec![0x01];
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    sha256(&data)
+}
+
+//RFC 9162 section 2.1.3.2
+fn verify_inclusion(index: usize, tree_size: usize, leaf: &Hash, proof: &[Hash], root: &Hash) -> bool {
+    if index >= tree_size {
+        return false;
+    }
+    let mut f = index;
+    let mut s = tree_size - 1;
+    let mut r = *leaf;
+    for p in proof {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            r = node(p, &r);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            r = node(&r, p);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    s == 0 && &r == root
+}
+
+//RFC 9162 section 2.1.4.2
+fn verify_consistency(first: usize, second: usize, first_root: &Hash, second_root: &Hash, proof: &[Hash]) -> bool {
+    if first == second {
+        return proof.is_empty() && first_root == second_root;
+    }
+    let mut proof = proof.to_vec();
+    if first.is_power_of_two() {
+        proof.insert(0, *first_root);
+    }
+    if proof.is_empty() {
+        return false;
+    }
+    let mut f = first - 1;
+    let mut s = second - 1;
+    while f & 1 == 1 {
+        f >>= 1;
+        s >>= 1;
+    }
+    let mut fr = proof[0];
+    let mut sr = proof[0];
+    for c in proof[1..].iter() {
+        if s == 0 {
+            return false;
+        }
+        if f & 1 == 1 || f == s {
+            fr = node(c, &fr);
+            sr = node(c, &sr);
+            while f & 1 == 0 && f != 0 {
+                f >>= 1;
+                s >>= 1;
+            }
+        } else {
+            sr = node(&sr, c);
+        }
+        f >>= 1;
+        s >>= 1;
+    }
+    &fr == first_root && &sr == second_root && s == 0
+}
+
+#[test]
+fn root_hash_matches_reference_vectors() {
+    assert_eq!(hex::encode(root_hash(&[])), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    let leaves = test_leaf_hashes();
+    for (size, expected) in TEST_ROOTS.iter().enumerate() {
+        assert_eq!(&hex::encode(root_hash(&leaves[..size + 1])), expected, "tree size {}", size + 1);
+    }
+}
+
+#[test]
+fn merkle_tree_root_matches_root_hash() {
+    //Enough leaves for a few levels of complete and incomplete subtrees
+    let leaves: Vec<Hash> = (0..40u8).map(|i| sha256(&[i])).collect();
+    let mut tree = MerkleTree::new();
+    assert_eq!(tree.root(), root_hash(&[]));
+    for (index, leaf) in leaves.iter().enumerate() {
+        tree.push(*leaf);
+        assert_eq!(tree.len(), index + 1);
+        assert_eq!(tree.leaf(index), Some(*leaf));
+        assert_eq!(tree.root(), root_hash(&leaves[..index + 1]), "tree size {}", index + 1);
+    }
+    assert_eq!(tree.leaf(leaves.len()), None);
+}
+
+#[test]
+fn inclusion_proofs_verify() {
+    let leaves = test_leaf_hashes();
+    let tree = test_tree(&leaves);
+    for tree_size in 1..=leaves.len() {
+        let root = root_hash(&leaves[..tree_size]);
+        for index in 0..tree_size {
+            let proof = tree.inclusion_path(index, tree_size);
+            assert!(verify_inclusion(index, tree_size, &leaves[index], &proof, &root), "index {} of tree size {}", index, tree_size);
+            //The proof must not hold for any other leaf
+            let other = leaves[(index + 1) % leaves.len()];
+            if other != leaves[index] {
+                assert!(!verify_inclusion(index, tree_size, &other, &proof, &root));
+            }
+        }
+    }
+}
+
+#[test]
+fn consistency_proofs_verify() {
+    let leaves = test_leaf_hashes();
+    let tree = test_tree(&leaves);
+    for second in 1..=leaves.len() {
+        let second_root = root_hash(&leaves[..second]);
+        for first in 1..=second {
+            let first_root = root_hash(&leaves[..first]);
+            let proof = tree.consistency_path(first, second);
+            assert!(verify_consistency(first, second, &first_root, &second_root, &proof), "tree sizes {} and {}", first, second);
+            //A forked history must not pass
+            if first < second {
+                let forked = node(&first_root, &first_root);
+                assert!(!verify_consistency(first, second, &forked, &second_root, &proof));
+            }
+        }
+    }
+}
+
+//Envelope checks of `envelope.rs` that strict mode relies on
+
+fn field(length: usize) -> String {
+    base64::encode(&vec![0xab; length])
+}
+
+#[test]
+fn envelope_parse_cases() {
+    let iv = field(32);
+    let ciphertext = field(48);
+    let hmac = field(32);
+    let cases: Vec<(&str, String, bool)> = vec![
+        ("unversioned", format!("{}|{}|{}", iv, ciphertext, hmac), true),
+        ("version 1", format!("1|{}|{}|{}", iv, ciphertext, hmac), true),
+        ("unknown version", format!("2|{}|{}|{}", iv, ciphertext, hmac), false),
+        ("empty version", format!("|{}|{}|{}", iv, ciphertext, hmac), false),
+        ("plaintext", "hello there".to_string(), false),
+        ("too few fields", format!("{}|{}", iv, ciphertext), false),
+        ("too many fields", format!("1|{}|{}|{}|{}", iv, ciphertext, hmac, hmac), false),
+        ("bad base64 IV", format!("{}|{}|{}", "not*base64", ciphertext, hmac), false),
+        ("bad base64 ciphertext", format!("{}|{}|{}", iv, "not*base64", hmac), false),
+        ("bad base64 HMAC", format!("{}|{}|{}", iv, ciphertext, "not*base64"), false),
+        ("15 byte IV", format!("{}|{}|{}", field(15), ciphertext, hmac), false),
+        ("16 byte IV", format!("{}|{}|{}", field(16), ciphertext, hmac), true),
+        ("32 byte IV", format!("{}|{}|{}", field(32), ciphertext, hmac), true),
+        ("33 byte IV", format!("{}|{}|{}", field(33), ciphertext, hmac), false),
+        ("empty ciphertext", format!("{}||{}", iv, hmac), false),
+        ("single block ciphertext", format!("{}|{}|{}", iv, field(16), hmac), true),
+        ("partial block ciphertext", format!("{}|{}|{}", iv, field(47), hmac), false),
+        ("31 byte HMAC", format!("{}|{}|{}", iv, ciphertext, field(31)), false),
+        ("33 byte HMAC", format!("{}|{}|{}", iv, ciphertext, field(33)), false),
+        ("empty HMAC", format!("{}|{}|", iv, ciphertext), false),
+    ];
+    for (name, message, valid) in cases {
+        assert_eq!(envelope::parse(&message).is_ok(), valid, "{}", name);
+    }
+}
+
+#[test]
+fn envelope_parse_keeps_fields() {
+    let parsed = envelope::parse(&format!("1|{}|{}|{}", field(16), field(64), field(32))).unwrap();
+    assert_eq!(parsed.iv.len(), 16);
+    assert_eq!(parsed.ciphertext.len(), 64);
+    assert_eq!(parsed.hmac.len(), 32);
+}
+
+#[test]
+fn envelope_length_matches_client_envelopes() {
+    for ciphertext_length in [16, 32, 48, 256, 4096].iter() {
+        let message = format!("{}|{}|{}", field(32), field(*ciphertext_length), field(32));
+        assert_eq!(envelope::envelope_length(*ciphertext_length), Some(message.len()));
+    }
+    assert_eq!(envelope::envelope_length(usize::MAX), None);
+}
+
+#[test]
+fn pad_to_bucket_pads_to_smallest_fitting_bucket() {
+    let mut message = "User abc has join the room".to_string();
+    envelope::pad_to_bucket(&mut message, &[16, 64, usize::MAX]);
+    assert_eq!(Some(message.len()), envelope::envelope_length(16));
+    let mut message = "x".repeat(1000);
+    envelope::pad_to_bucket(&mut message, &[16, 64]);
+    assert_eq!(message.len(), 1000);
+}
+
+#[test]
+fn pad_frame_keeps_json_valid() {
+    let text = format!("{{\"message_id\":1,\"id\":\"0123456789abcdef\",\"message\":\"{}|{}|{}\",\"timestamp\":0}}", field(32), field(64), field(32));
+    let event = "{\"Left\":{\"id\":\"0123456789abcdef\"}}".to_string();
+    let (mut padded_text, mut padded_event) = (text.clone(), event.clone());
+    envelope::pad_frame(&mut padded_text, &[64]);
+    envelope::pad_frame(&mut padded_event, &[64]);
+    //A text of a bucket and a small event come out the same size
+    assert_eq!(padded_text.len(), padded_event.len());
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&padded_event).unwrap(), serde_json::from_str::<serde_json::Value>(&event).unwrap());
+}
+
+//Room history of `history.rs`
+
+fn stored(message_id: u64, parent_id: Option<u64>, expires_at: Option<u64>) -> StoredMessage {
+    StoredMessage {
+        message_id,
+        id: Some("sender".to_string()),
+        message: format!("message {}", message_id),
+        timestamp: 0,
+        parent_id,
+        expires_at,
+        edited: false,
+        reactions: HashMap::new(),
+    }
+}
+
+#[test]
+fn history_drops_oldest_when_full() {
+    let mut history = History::new();
+    for message_id in 0..150 {
+        history.push(stored(message_id, None, None));
+    }
+    let replay = history.replay();
+    assert_eq!(replay.len(), 100);
+    assert_eq!(replay[0].message_id, 50);
+    assert!(history.get(49).is_none());
+    assert!(history.get(149).is_some());
+}
+
+#[test]
+fn history_removes_messages() {
+    let mut history = History::new();
+    history.push(stored(0, None, None));
+    history.push(stored(1, None, None));
+    assert!(history.remove(1).is_some());
+    assert!(history.remove(1).is_none());
+    assert!(history.get(1).is_none());
+    assert_eq!(history.replay().len(), 1);
+}
+
+#[test]
+fn history_thread_follows_replies_to_replies() {
+    let mut history = History::new();
+    history.push(stored(0, None, None));
+    history.push(stored(1, Some(0), None));
+    history.push(stored(2, None, None));
+    history.push(stored(3, Some(1), None));
+    history.push(stored(4, Some(2), None));
+    let thread: Vec<u64> = history.thread(0).iter().map(|message| message.message_id).collect();
+    assert_eq!(thread, vec![0, 1, 3]);
+    assert!(history.thread(99).is_empty());
+}
+
+#[test]
+fn history_expires_messages() {
+    let mut history = History::new();
+    history.push(stored(0, None, Some(10)));
+    history.push(stored(1, None, None));
+    history.push(stored(2, None, Some(20)));
+    assert_eq!(history.expire(9), Vec::<u64>::new());
+    assert_eq!(history.expire(10), vec![0]);
+    assert_eq!(history.expire(u64::MAX), vec![2]);
+    assert_eq!(history.replay().len(), 1);
+    assert!(history.get(1).is_some());
+}
+
+//Quorum arithmetic of votes to remove a member
+
+#[test]
+fn removal_votes_required_cases() {
+    //(members, quorum, votes required)
+    let cases = [
+        (2, 1, 1), (2, 51, 1), (2, 100, 1),
+        (3, 1, 1), (3, 51, 2), (3, 100, 2),
+        (10, 1, 1), (10, 51, 5), (10, 100, 9),
+    ];
+    for (members, quorum, required) in cases.iter() {
+        assert_eq!(removal_votes_required(*members, *quorum), *required, "{} members at {}%", members, quorum);
+    }
+}
+
+//Timestamp rounding of privacy mode
+
+#[test]
+fn round_down_cases() {
+    //No rounding without a granularity
+    assert_eq!(round_down(125, 0), 125);
+    assert_eq!(round_down(125, 1), 125);
+    assert_eq!(round_down(125, 60), 120);
+    //Already on a boundary
+    assert_eq!(round_down(0, 60), 0);
+    assert_eq!(round_down(120, 60), 120);
+    assert_eq!(round_down(u64::MAX, 0), u64::MAX);
+    assert_eq!(round_down(u64::MAX, 60), u64::MAX - u64::MAX % 60);
+}
+
+#[test]
+fn round_up_cases() {
+    //No rounding without a granularity
+    assert_eq!(round_up(125, 0), 125);
+    assert_eq!(round_up(125, 1), 125);
+    assert_eq!(round_up(121, 60), 180);
+    assert_eq!(round_up(179, 60), 180);
+    //Already on a boundary
+    assert_eq!(round_up(0, 60), 0);
+    assert_eq!(round_up(180, 60), 180);
+    //No multiple of the granularity is that high so the result saturates
+    assert_eq!(round_up(u64::MAX, 60), u64::MAX);
+    assert_eq!(round_up(u64::MAX - 1, 60), u64::MAX);
+    assert_eq!(round_up(u64::MAX, 1), u64::MAX);
+    //The highest multiple is still reachable
+    let highest = u64::MAX - u64::MAX % 60;
+    assert_eq!(round_up(highest - 1, 60), highest);
+    assert_eq!(round_up(highest, 60), highest);
+}
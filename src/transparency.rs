@@ -0,0 +1,431 @@
+use actix_web::{web, HttpResponse};
+
+use serde::{Serialize, Deserialize};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::{Signer, Verifier};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::Mutex;
+
+use crate::chatserver::get_unix_time;
+
+//Module `transparency.rs` keeps an append-only Merkle log (RFC 6962 style) of identity keys
+//the server hands out. Clients and auditors can fetch signed tree heads along with inclusion
+//and consistency proofs to check that nobody's key was silently swapped. Entries are appended
+//to a file on disk, one JSON entry per line, and reloaded when the server starts
+
+//Hash of a leaf is prefixed with 0x00 and hash of an interior node with 0x01 so the two can't be confused
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub(crate) type Hash = [u8; 32];
+
+//A single entry of the log
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogEntry {
+    pub identity: String,
+    pub public_key: String,
+    //Time is given in terms of seconds since UNIX epoch
+    pub timestamp: u64,
+}
+
+//Tree head signed with the server's private key ... the signature covers the JSON encoding of
+//`{"tree_size":...,"root_hash":...,"timestamp":...}`
+#[derive(Serialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    //Hex encoded
+    pub root_hash: String,
+    pub timestamp: u64,
+    //Hex encoded
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+struct UnsignedTreeHead {
+    tree_size: u64,
+    root_hash: String,
+    timestamp: u64,
+}
+
+//An entry along with its position in the log
+#[derive(Serialize)]
+pub struct IndexedEntry {
+    pub index: u64,
+    pub entry: LogEntry,
+    //Hex encoded hash of the entry as a leaf of the tree, see `hash_leaf`
+    pub leaf_hash: String,
+}
+
+//Hex encoded hashes needed to recompute a root hash
+#[derive(Serialize)]
+pub struct Proof {
+    pub hashes: Vec<String>,
+}
+
+//Body of a request to publish a key ... `public_key` is PEM encoded and `signature` is the hex encoded
+//SHA-256 signature of `{"identity":...,"public_key":...}`. The first key of an identity signs itself
+//and every later key is signed by the key it replaces, so only the holder of a key can publish it
+#[derive(Deserialize)]
+pub struct PublishRequest {
+    pub identity: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+struct SignedKey<'a> {
+    identity: &'a String,
+    public_key: &'a String,
+}
+
+//Merkle tree that keeps the hash of every complete subtree so that roots and proofs don't need
+//the whole log rehashed ... `levels[h][i]` is the hash of the 2^h leaves starting at leaf i * 2^h
+pub(crate) struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+    //Root hash of every leaf, updated as leaves are appended
+    root: Hash,
+}
+
+pub struct TransparencyLog {
+    entries: Vec<LogEntry>,
+    //Tree over the leaf hashes of the entries in the same order
+    tree: MerkleTree,
+    //Maps identities to the index of their latest entry
+    latest: HashMap<String, usize>,
+    file: File,
+    signing_key: PKey<Private>,
+}
+
+//SHA-256 of the byte 0x00 followed by the entry as compact JSON with its fields in the order
+//`identity`, `public_key`, `timestamp` ... the same bytes as the entry's line in the log file
+fn hash_leaf(entry: &LogEntry) -> Hash {
+    let mut data = vec![LEAF_PREFIX];
+    data.extend_from_slice(serde_json::to_string(entry).unwrap().as_bytes());
+    sha256(&data)
+}
+
+fn hash_children(left: &Hash, right: &Hash) -> Hash {
+    let mut data = vec![NODE_PREFIX];
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    sha256(&data)
+}
+
+//Largest power of two strictly less than n ... n must be at least 2
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+//Merkle tree hash of a list of leaves
+pub(crate) fn root_hash(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => sha256(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split_point(n);
+            hash_children(&root_hash(&leaves[..k]), &root_hash(&leaves[k..]))
+        },
+    }
+}
+
+//Audit path of the leaf at index m among the n leaves starting at `start` ... `subtree(start, len)`
+//gives the hash of the len leaves starting at `start`
+fn audit_path(m: usize, start: usize, n: usize, subtree: &dyn Fn(usize, usize) -> Hash) -> Vec<Hash> {
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k = split_point(n);
+    if m < k {
+        let mut path = audit_path(m, start, k, subtree);
+        path.push(subtree(start + k, n - k));
+        path
+    } else {
+        let mut path = audit_path(m - k, start + k, n - k, subtree);
+        path.push(subtree(start, k));
+        path
+    }
+}
+
+//Consistency proof between the first m and every one of the n leaves starting at `start`
+fn subproof(m: usize, start: usize, n: usize, complete_subtree: bool, subtree: &dyn Fn(usize, usize) -> Hash) -> Vec<Hash> {
+    if m == n {
+        return if complete_subtree { Vec::new() } else { vec![subtree(start, n)] };
+    }
+    let k = split_point(n);
+    if m <= k {
+        let mut path = subproof(m, start, k, complete_subtree, subtree);
+        path.push(subtree(start + k, n - k));
+        path
+    } else {
+        let mut path = subproof(m - k, start + k, n - k, false, subtree);
+        path.push(subtree(start, k));
+        path
+    }
+}
+
+impl MerkleTree {
+    pub(crate) fn new() -> MerkleTree {
+        MerkleTree {
+            levels: vec![Vec::new()],
+            root: root_hash(&[]),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub(crate) fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub(crate) fn leaf(&self, index: usize) -> Option<Hash> {
+        self.levels[0].get(index).copied()
+    }
+
+    //Adds a leaf and every subtree it completes
+    pub(crate) fn push(&mut self, leaf: Hash) {
+        let mut level = 0;
+        let mut hash = leaf;
+        loop {
+            self.levels[level].push(hash);
+            let index = self.levels[level].len() - 1;
+            //A left child has to wait for its sibling
+            if index & 1 == 0 {
+                break;
+            }
+            hash = hash_children(&self.levels[level][index - 1], &hash);
+            level += 1;
+            if level == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+        }
+        self.root = self.subtree_hash(0, self.len());
+    }
+
+    //Hash of the len leaves starting at `start` ... ranges are always split into complete subtrees
+    //that line up with `levels`, the way the proofs split them
+    fn subtree_hash(&self, start: usize, len: usize) -> Hash {
+        if len == 0 {
+            return root_hash(&[]);
+        }
+        if len.is_power_of_two() {
+            let level = len.trailing_zeros() as usize;
+            return self.levels[level][start >> level];
+        }
+        let k = split_point(len);
+        hash_children(&self.subtree_hash(start, k), &self.subtree_hash(start + k, len - k))
+    }
+
+    //Audit path of the leaf at index m in the tree of the first `tree_size` leaves
+    pub(crate) fn inclusion_path(&self, m: usize, tree_size: usize) -> Vec<Hash> {
+        audit_path(m, 0, tree_size, &|start, len| self.subtree_hash(start, len))
+    }
+
+    //Proof that the tree of the first m leaves is a prefix of the tree of the first `tree_size` leaves
+    pub(crate) fn consistency_path(&self, m: usize, tree_size: usize) -> Vec<Hash> {
+        subproof(m, 0, tree_size, true, &|start, len| self.subtree_hash(start, len))
+    }
+}
+
+fn encode_hashes(hashes: Vec<Hash>) -> Proof {
+    Proof {
+        hashes: hashes.iter().map(hex::encode).collect(),
+    }
+}
+
+impl TransparencyLog {
+    //Loads the log stored at `path` (creating it if needed) ... tree heads are signed with the key at `private_key_path`
+    pub fn open(path: &str, private_key_path: &str) -> std::io::Result<TransparencyLog> {
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let pem = std::fs::read(private_key_path)?;
+        let signing_key = PKey::private_key_from_pem(&pem)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let mut contents = Vec::new();
+        (&file).read_to_end(&mut contents)?;
+        let mut log = TransparencyLog {
+            entries: Vec::new(),
+            tree: MerkleTree::new(),
+            latest: HashMap::new(),
+            file,
+            signing_key,
+        };
+        let mut offset = 0;
+        for line in contents.split(|byte| *byte == b'\n') {
+            let end = offset + line.len();
+            //Every entry written in full is followed by a newline
+            let complete = end < contents.len();
+            if !line.iter().all(|byte| byte.is_ascii_whitespace()) {
+                match serde_json::from_slice::<LogEntry>(line) {
+                    Ok(entry) => {
+                        //The entry made it to disk but its newline did not
+                        if !complete {
+                            writeln!(log.file)?;
+                        }
+                        log.push(entry);
+                    },
+                    //The server stopped while appending ... drop the half written entry
+                    Err(_) if !complete => {
+                        log.file.set_len(offset as u64)?;
+                    },
+                    Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+                }
+            }
+            offset = end + 1;
+        }
+        Ok(log)
+    }
+
+    fn push(&mut self, entry: LogEntry) -> usize {
+        let index = self.entries.len();
+        self.tree.push(hash_leaf(&entry));
+        self.latest.insert(entry.identity.clone(), index);
+        self.entries.push(entry);
+        index
+    }
+
+    //Checks that the key of a request is signed by the identity's latest key or, for a new identity, by itself
+    pub fn verify_publication(&self, request: &PublishRequest) -> Result<(), &'static str> {
+        let signing_key = match self.lookup(&request.identity) {
+            None => &request.public_key,
+            Some(latest) => &self.entries[latest.index as usize].public_key,
+        };
+        let signing_key = PKey::public_key_from_pem(signing_key.as_bytes())
+            .map_err(|_| "The public key is not a PEM encoded key")?;
+        PKey::public_key_from_pem(request.public_key.as_bytes())
+            .map_err(|_| "The public key is not a PEM encoded key")?;
+        let signature = hex::decode(&request.signature).map_err(|_| "The signature is not hex encoded")?;
+        let signed = serde_json::to_string(&SignedKey {
+            identity: &request.identity,
+            public_key: &request.public_key,
+        }).unwrap();
+
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &signing_key)
+            .map_err(|_| "The key type is not supported")?;
+        verifier.update(signed.as_bytes()).map_err(|_| "The signature could not be checked")?;
+        match verifier.verify(&signature) {
+            Ok(true) => Ok(()),
+            _ => Err("The key must be signed by the identity's latest key, or by itself for a new identity"),
+        }
+    }
+
+    //Writes the entry to disk before adding it to the tree ... returns the index of the entry
+    pub fn append(&mut self, identity: String, public_key: String) -> std::io::Result<usize> {
+        let entry = LogEntry {
+            identity,
+            public_key,
+            timestamp: get_unix_time(),
+        };
+        writeln!(self.file, "{}", serde_json::to_string(&entry).unwrap())?;
+        self.file.sync_data()?;
+        Ok(self.push(entry))
+    }
+
+    pub fn tree_head(&self) -> SignedTreeHead {
+        let unsigned = UnsignedTreeHead {
+            tree_size: self.tree.len() as u64,
+            root_hash: hex::encode(self.tree.root()),
+            timestamp: get_unix_time(),
+        };
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.signing_key).unwrap();
+        signer.update(serde_json::to_string(&unsigned).unwrap().as_bytes()).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+        SignedTreeHead {
+            tree_size: unsigned.tree_size,
+            root_hash: unsigned.root_hash,
+            timestamp: unsigned.timestamp,
+            signature: hex::encode(signature),
+        }
+    }
+
+    pub fn entry(&self, index: usize) -> Option<IndexedEntry> {
+        match (self.entries.get(index), self.tree.leaf(index)) {
+            (Some(entry), Some(leaf)) => Some(IndexedEntry {
+                index: index as u64,
+                entry: entry.clone(),
+                leaf_hash: hex::encode(leaf),
+            }),
+            _ => None,
+        }
+    }
+
+    //Latest key published for an identity
+    pub fn lookup(&self, identity: &String) -> Option<IndexedEntry> {
+        self.latest.get(identity).and_then(|index| self.entry(*index))
+    }
+
+    //Proof that the entry at `index` is in the tree of the first `tree_size` entries
+    pub fn inclusion_proof(&self, index: usize, tree_size: usize) -> Option<Proof> {
+        if index >= tree_size || tree_size > self.tree.len() {
+            return None;
+        }
+        Some(encode_hashes(self.tree.inclusion_path(index, tree_size)))
+    }
+
+    //Proof that the tree of the first `first` entries is a prefix of the tree of the first `second` entries
+    pub fn consistency_proof(&self, first: usize, second: usize) -> Option<Proof> {
+        if first == 0 || first > second || second > self.tree.len() {
+            return None;
+        }
+        Some(encode_hashes(self.tree.consistency_path(first, second)))
+    }
+}
+
+//HTTP endpoints for clients and auditors
+
+pub fn publish(log: web::Data<Mutex<TransparencyLog>>, request: web::Json<PublishRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    let mut log = log.lock().unwrap();
+    if let Err(reason) = log.verify_publication(&request) {
+        return HttpResponse::Forbidden().body(reason);
+    }
+    match log.append(request.identity, request.public_key) {
+        Ok(index) => HttpResponse::Ok().json(log.entry(index)),
+        Err(_) => HttpResponse::InternalServerError().body("Something went wrong..."),
+    }
+}
+
+pub fn tree_head(log: web::Data<Mutex<TransparencyLog>>) -> HttpResponse {
+    HttpResponse::Ok().json(log.lock().unwrap().tree_head())
+}
+
+pub fn entry(log: web::Data<Mutex<TransparencyLog>>, index: web::Path<usize>) -> HttpResponse {
+    match log.lock().unwrap().entry(index.into_inner()) {
+        Some(entry) => HttpResponse::Ok().json(entry),
+        None => HttpResponse::NotFound().body("404"),
+    }
+}
+
+pub fn lookup(log: web::Data<Mutex<TransparencyLog>>, identity: web::Path<String>) -> HttpResponse {
+    match log.lock().unwrap().lookup(&identity.into_inner()) {
+        Some(entry) => HttpResponse::Ok().json(entry),
+        None => HttpResponse::NotFound().body("404"),
+    }
+}
+
+pub fn inclusion_proof(log: web::Data<Mutex<TransparencyLog>>, path: web::Path<(usize, usize)>) -> HttpResponse {
+    let (index, tree_size) = path.into_inner();
+    match log.lock().unwrap().inclusion_proof(index, tree_size) {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::BadRequest().body("Invalid index or tree size"),
+    }
+}
+
+pub fn consistency_proof(log: web::Data<Mutex<TransparencyLog>>, path: web::Path<(usize, usize)>) -> HttpResponse {
+    let (first, second) = path.into_inner();
+    match log.lock().unwrap().consistency_proof(first, second) {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::BadRequest().body("Invalid tree sizes"),
+    }
+}